@@ -0,0 +1,614 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use crate::anki::import::infer_minutes_west;
+use crate::anki::{temp_collection_path, AnkiCollection, SCHEMA};
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue, CardType};
+use crate::srs::collection::DEFAULT_DECK_ID;
+use crate::srs::config::Config;
+use crate::svc::clock::Clock;
+
+// the note type given to exported cards, which have no content of their own
+const MODEL_ID: i64 = 1_614_556_800_000;
+
+fn export_error(err: impl ToString) -> Error {
+    Error::Export(err.to_string())
+}
+
+/// Write a deck package Anki can import, keeping each card's schedule and
+/// review history. Cards get placeholder notes saying which card they are.
+pub fn export_apkg<P: AsRef<Path>>(
+    path: P,
+    anki: &AnkiCollection,
+    clock: &dyn Clock,
+) -> Result<(), Error> {
+    let temp = temp_collection_path();
+    let exported = export_collection(&temp, anki, clock).and_then(|_| {
+        let mut zip = ZipWriter::new(File::create(path).map_err(export_error)?);
+        zip.start_file("collection.anki2", FileOptions::default())
+            .map_err(export_error)?;
+        zip.write_all(&fs::read(&temp).map_err(export_error)?)
+            .map_err(export_error)?;
+        zip.start_file("media", FileOptions::default())
+            .map_err(export_error)?;
+        zip.write_all(b"{}").map_err(export_error)?;
+        zip.finish().map_err(export_error)?;
+        Ok(())
+    });
+    let _ = fs::remove_file(&temp);
+    exported
+}
+
+/// How due days map onto Anki's, which count from the collection's
+/// creation.
+struct Days {
+    crt: i64,
+    minutes_west: i32,
+    // subtracted from due days
+    offset: i64,
+}
+
+impl Days {
+    /// Configs counting days from the epoch get a creation time on the
+    /// earliest day a card is due, so Anki's day numbers stay positive.
+    fn new(config: &Config, cards: &[Card], clock: &dyn Clock) -> Self {
+        if let Some(crt) = config.collection_created {
+            return Self {
+                crt,
+                minutes_west: config.collection_created_minutes_west,
+                offset: 0,
+            };
+        }
+        let cut_off = config.day_cut_off(clock);
+        let today = config.day_today(clock);
+        let first = cards
+            .iter()
+            .filter(|card| due_in_days(card))
+            .map(|card| card.due)
+            .chain(std::iter::once(today))
+            .min()
+            .unwrap_or(today);
+        let crt = cut_off - (today - first + 1) * 86_400;
+        Self {
+            crt,
+            minutes_west: infer_minutes_west(crt, config.rollover_hour),
+            offset: first,
+        }
+    }
+}
+
+/// Reviews and interday learning are due on a day, rather than a position
+/// or a time.
+fn due_in_days(card: &Card) -> bool {
+    match card.card_type {
+        CardType::New => false,
+        CardType::Review => true,
+        CardType::Learn | CardType::Relearn => card.due <= 1_000_000_000,
+    }
+}
+
+/// Write a `collection.anki2` file.
+pub fn export_collection<P: AsRef<Path>>(
+    path: P,
+    anki: &AnkiCollection,
+    clock: &dyn Clock,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let _ = fs::remove_file(path);
+    let mut db = Connection::open(path).map_err(export_error)?;
+    db.execute_batch(SCHEMA).map_err(export_error)?;
+
+    // cards in filtered decks go home, as Anki's filtered decks are
+    // searches that can't be carried over
+    let cards: Vec<Card> = anki.collection.cards.iter().map(home_card).collect();
+    let mut configs = anki.collection.configs.clone();
+    let base = anki
+        .collection
+        .deck(DEFAULT_DECK_ID)
+        .and_then(|deck| deck.config_id)
+        .and_then(|id| configs.get(&id))
+        .or_else(|| configs.values().next())
+        .cloned()
+        .unwrap_or_default();
+    configs.entry(1).or_insert_with(|| base.clone());
+    let days = Days::new(&base, &cards, clock);
+    let now = clock.now();
+
+    // notes of the cards, with siblings sharing one
+    let mut notes: BTreeMap<i64, Vec<&Card>> = BTreeMap::new();
+    let mut last_note_id = cards.iter().map(|card| card.note_id).max().unwrap_or(0);
+    for card in &cards {
+        let note_id = if card.note_id == 0 {
+            last_note_id += 1;
+            last_note_id
+        } else {
+            card.note_id
+        };
+        notes.entry(note_id).or_default().push(card);
+    }
+    let templates = notes.values().map(Vec::len).max().unwrap_or(1);
+
+    let mut decks = serde_json::Map::new();
+    for deck in anki.collection.decks.iter().filter(|d| d.filter.is_none()) {
+        let conf = deck.config_id.unwrap_or(1);
+        decks.insert(deck.id.to_string(), deck_json(deck.id, &deck.name, conf));
+    }
+    decks
+        .entry(DEFAULT_DECK_ID.to_string())
+        .or_insert_with(|| deck_json(DEFAULT_DECK_ID, "Default", 1));
+    let dconf: serde_json::Map<String, Value> = configs
+        .iter()
+        .map(|(id, config)| (id.to_string(), deck_config_json(*id, config)))
+        .collect();
+    let conf = json!({
+        "rollover": base.rollover_hour,
+        "creationOffset": days.minutes_west,
+        "schedVer": 2,
+        "activeDecks": [DEFAULT_DECK_ID],
+        "curDeck": DEFAULT_DECK_ID,
+        "curModel": MODEL_ID,
+        "nextPos": cards
+            .iter()
+            .filter(|card| card.card_type == CardType::New)
+            .map(|card| card.due + 1)
+            .max()
+            .unwrap_or(1),
+        "sortType": "noteFld",
+        "sortBackwards": false,
+        "addToCur": true,
+        "collapseTime": 1_200,
+        "newSpread": 0,
+        "dueCounts": true,
+        "estTimes": true,
+        "timeLim": 0,
+    });
+
+    let tx = db.transaction().map_err(export_error)?;
+    tx.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+        params![
+            days.crt,
+            now * 1_000,
+            conf.to_string(),
+            json!({ MODEL_ID.to_string(): model_json(templates) }).to_string(),
+            Value::Object(decks).to_string(),
+            Value::Object(dconf).to_string()
+        ],
+    )
+    .map_err(export_error)?;
+
+    for (note_id, siblings) in &notes {
+        let front = format!("card {}", siblings[0].id);
+        tx.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, 0, '', ?5, ?6, 0, 0, '')",
+            params![
+                note_id,
+                format!("sm2-{}", note_id),
+                MODEL_ID,
+                now,
+                format!("{}\x1f", front),
+                front
+            ],
+        )
+        .map_err(export_error)?;
+
+        for (ord, card) in siblings.iter().enumerate() {
+            let due = if due_in_days(card) {
+                card.due - days.offset
+            } else {
+                card.due
+            };
+            let left = card
+                .learning_state
+                .map_or(0, |state| state.remaining_today * 1_000 + state.remaining);
+            tx.execute(
+                "INSERT INTO cards VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6, ?7, ?8, ?9, ?10, ?11, ?12,
+                 ?13, 0, 0, 0, '')",
+                params![
+                    card.id,
+                    note_id,
+                    card.deck_id,
+                    ord as i64,
+                    now,
+                    card.card_type as i8,
+                    anki_queue(card.card_queue),
+                    due,
+                    card.interval,
+                    card.ease_factor,
+                    card.reps,
+                    card.lapses,
+                    left
+                ],
+            )
+            .map_err(export_error)?;
+        }
+    }
+
+    // revlog ids are millisecond timestamps, nudged apart when two reviews
+    // share one
+    let mut last_id = 0;
+    for log in &anki.review_logs {
+        let id = (log.timestamp * 1_000).max(last_id + 1);
+        last_id = id;
+        tx.execute(
+            "INSERT INTO revlog VALUES (?1, ?2, 0, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                id,
+                log.card_id,
+                log.choice as i8,
+                log.interval,
+                log.last_interval,
+                log.ease_factor,
+                log.time_taken,
+                log.review_kind as i8
+            ],
+        )
+        .map_err(export_error)?;
+    }
+    tx.commit().map_err(export_error)
+}
+
+fn home_card(card: &Card) -> Card {
+    let mut card = card.clone();
+    if let Some(deck_id) = card.original_deck_id.take() {
+        card.deck_id = deck_id;
+        if let Some(due) = card.original_due.take() {
+            if card.card_queue != CardQueue::Learn {
+                card.due = due;
+            }
+        }
+        if card.card_queue == CardQueue::Preview {
+            card.card_queue = card.queue_for_type();
+        }
+    }
+    card
+}
+
+fn anki_queue(queue: CardQueue) -> i8 {
+    match queue {
+        // Anki unburies these at the next day, like we do
+        CardQueue::Buried => -2,
+        _ => queue as i8,
+    }
+}
+
+fn deck_json(id: i64, name: &str, conf: i64) -> Value {
+    json!({
+        "id": id,
+        "name": name,
+        "conf": conf,
+        "dyn": 0,
+        "desc": "",
+        "mod": 0,
+        "usn": 0,
+        "collapsed": false,
+        "browserCollapsed": false,
+        "newToday": [0, 0],
+        "revToday": [0, 0],
+        "lrnToday": [0, 0],
+        "timeToday": [0, 0],
+        "extendNew": 0,
+        "extendRev": 0,
+    })
+}
+
+fn deck_config_json(id: i64, config: &Config) -> Value {
+    json!({
+        "id": id,
+        "name": if id == 1 { "Default".to_string() } else { format!("Options {}", id) },
+        "mod": 0,
+        "usn": 0,
+        "dyn": false,
+        "maxTaken": 60,
+        "timer": 0,
+        "autoplay": true,
+        "replayq": true,
+        "buryInterdayLearning": config.bury_interday_learning,
+        "new": {
+            "delays": config.learn_steps,
+            "ints": [config.graduating_interval_good, config.graduating_interval_easy, 0],
+            "initialFactor": config.initial_ease,
+            "perDay": config.new_per_day,
+            "bury": config.bury_new,
+            "order": 1,
+            "separate": true,
+        },
+        "lapse": {
+            "delays": config.relearn_steps,
+            "mult": config.lapse_multiplier,
+            "minInt": config.minimum_review_interval,
+            "leechFails": config.leech_threshold,
+            "leechAction": 0,
+        },
+        "rev": {
+            "perDay": config.reviews_per_day,
+            "ease4": config.easy_multiplier,
+            "hardFactor": config.hard_multiplier,
+            "ivlFct": config.interval_multiplier,
+            "maxIvl": config.maximum_review_interval,
+            "bury": config.bury_reviews,
+            "fuzz": 0.05,
+            "minSpace": 1,
+        },
+    })
+}
+
+/// A front and back note type, with a template for each sibling.
+fn model_json(templates: usize) -> Value {
+    let field = |name: &str, ord: usize| {
+        json!({
+            "name": name, "ord": ord, "sticky": false, "rtl": false,
+            "font": "Arial", "size": 20, "media": [],
+        })
+    };
+    let tmpls: Vec<Value> = (0..templates)
+        .map(|ord| {
+            json!({
+                "name": format!("Card {}", ord + 1),
+                "ord": ord,
+                "qfmt": "{{Front}}",
+                "afmt": "{{FrontSide}}<hr id=answer>{{Back}}",
+                "bqfmt": "",
+                "bafmt": "",
+                "did": null,
+                "bfont": "",
+                "bsize": 0,
+            })
+        })
+        .collect();
+    json!({
+        "id": MODEL_ID,
+        "name": "sm2",
+        "type": 0,
+        "mod": 0,
+        "usn": 0,
+        "sortf": 0,
+        "did": DEFAULT_DECK_ID,
+        "tmpls": tmpls,
+        "flds": [field("Front", 0), field("Back", 1)],
+        "css": ".card { font-family: arial; font-size: 20px; }",
+        "latexPre": "\\documentclass[12pt]{article}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "latexsvg": false,
+        "req": (0..templates).map(|ord| json!([ord, "any", [0]])).collect::<Vec<_>>(),
+        "tags": [],
+        "vers": [],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anki::import::import_apkg;
+    use crate::srs::card::{LearningState, StepKind};
+    use crate::srs::collection::Collection;
+    use crate::srs::filtered::{Filter, Search};
+    use crate::srs::review_log::{ReviewKind, ReviewLog};
+    use crate::srs::scheduler::Choice;
+    use crate::srs::test_util::NOW;
+    use crate::svc::clock::FixedClock;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("sm2-test-{}-{}", std::process::id(), name))
+    }
+
+    fn sample(config: Config, today: i64) -> AnkiCollection {
+        let mut collection = Collection::default();
+        let vocab = collection.add_deck("Japanese::Vocab");
+        collection.add_card(DEFAULT_DECK_ID, Card::new(3)).unwrap();
+        collection
+            .add_card(
+                vocab,
+                Card {
+                    note_id: 7,
+                    card_type: CardType::Review,
+                    card_queue: CardQueue::Review,
+                    due: today + 3,
+                    interval: 10,
+                    ease_factor: 2_500,
+                    reps: 4,
+                    ..Card::default()
+                },
+            )
+            .unwrap();
+        collection
+            .add_card(
+                vocab,
+                Card {
+                    note_id: 7,
+                    card_type: CardType::Relearn,
+                    card_queue: CardQueue::DayLearn,
+                    due: today - 2,
+                    interval: 1,
+                    ease_factor: 2_300,
+                    lapses: 1,
+                    learning_state: Some(LearningState {
+                        kind: StepKind::Relearn,
+                        remaining: 2,
+                        remaining_today: 1,
+                    }),
+                    ..Card::default()
+                },
+            )
+            .unwrap();
+        collection
+            .add_card(
+                vocab,
+                Card {
+                    card_type: CardType::Learn,
+                    card_queue: CardQueue::Learn,
+                    due: NOW + 600,
+                    learning_state: LearningState::from_packed(2_002, CardType::Learn),
+                    ..Card::default()
+                },
+            )
+            .unwrap();
+        let cram =
+            collection.add_filtered_deck("Cram", Filter::new(Search::Deck { deck_id: vocab }));
+        collection
+            .add_card(
+                cram,
+                Card {
+                    card_type: CardType::Review,
+                    card_queue: CardQueue::Review,
+                    due: today,
+                    interval: 5,
+                    ease_factor: 2_500,
+                    original_deck_id: Some(vocab),
+                    original_due: Some(today + 4),
+                    ..Card::default()
+                },
+            )
+            .unwrap();
+
+        let log = |timestamp, choice| ReviewLog {
+            card_id: 2,
+            timestamp,
+            choice,
+            interval: 10,
+            last_interval: 4,
+            ease_factor: 2_500,
+            last_ease_factor: 2_500,
+            card_type: CardType::Review,
+            time_taken: 3_000,
+            review_kind: ReviewKind::Review,
+        };
+        collection.configs.insert(
+            1,
+            Config {
+                learn_steps: vec![2.0, 20.0],
+                new_per_day: 15,
+                bury_new: true,
+                ..config
+            },
+        );
+        for deck in &mut collection.decks {
+            if deck.filter.is_none() {
+                deck.config_id = Some(1);
+            }
+        }
+        AnkiCollection {
+            collection,
+            review_logs: vec![log(NOW - 100, Choice::Ok), log(NOW - 100, Choice::Easy)],
+        }
+    }
+
+    fn round_trip(anki: &AnkiCollection, clock: &FixedClock) -> AnkiCollection {
+        let apkg = temp_path(&format!("export-{}.apkg", clock.now()));
+        export_apkg(&apkg, anki, clock).unwrap();
+        let imported = import_apkg(&apkg).unwrap();
+        fs::remove_file(&apkg).unwrap();
+        imported
+    }
+
+    #[test]
+    fn test_epoch_days() {
+        let clock = FixedClock::new(NOW, 0);
+        let config = Config::default();
+        let today = config.day_today(&clock);
+        let anki = sample(config, today);
+        let imported = round_trip(&anki, &clock);
+
+        // due days count from the earliest due day instead of the epoch, and
+        // stay the same distance from today
+        let config = &imported.collection.configs[&1];
+        assert_eq!(config.collection_created_minutes_west, 0);
+        let today = config.day_today(&clock);
+        assert_eq!(today, 2);
+        let cards = &imported.collection.cards;
+        assert_eq!(cards[1].due, today + 3);
+        assert_eq!(cards[2].due, today - 2);
+        assert_eq!(cards[2].card_queue, CardQueue::DayLearn);
+        assert_eq!(
+            cards[2].learning_state,
+            anki.collection.cards[2].learning_state
+        );
+        // times and positions are kept as they are
+        assert_eq!(cards[0].due, 3);
+        assert_eq!(cards[3].due, NOW + 600);
+        assert_eq!(
+            cards[3].learning_state,
+            LearningState::from_packed(2_002, CardType::Learn)
+        );
+        // the filtered deck isn't exported
+        assert_eq!(cards[4].deck_id, anki.collection.cards[1].deck_id);
+        assert_eq!(cards[4].due, today + 4);
+        assert_eq!(imported.collection.decks.len(), 2);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let clock = FixedClock::new(NOW, -600);
+        // created 2021-02-01 04:00 at UTC+10
+        let config = Config {
+            collection_created: Some(1_612_116_000),
+            collection_created_minutes_west: -600,
+            ..Config::default()
+        };
+        let today = config.day_today(&clock);
+        assert_eq!(today, 28);
+        let anki = sample(config, today);
+        let imported = round_trip(&anki, &clock);
+
+        let names: Vec<&str> = imported
+            .collection
+            .decks
+            .iter()
+            .map(|deck| deck.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Default", "Japanese::Vocab"]);
+        let config_ids: Vec<Option<i64>> = imported
+            .collection
+            .decks
+            .iter()
+            .map(|deck| deck.config_id)
+            .collect();
+        assert_eq!(config_ids, vec![Some(1), Some(1)]);
+
+        let config = &imported.collection.configs[&1];
+        assert_eq!(config.learn_steps, vec![2.0, 20.0]);
+        assert_eq!(config.new_per_day, 15);
+        assert!(config.bury_new);
+        assert_eq!(config.collection_created, Some(1_612_116_000));
+        assert_eq!(config.day_today(&clock), today);
+
+        for (card, original) in imported.collection.cards.iter().zip(&anki.collection.cards) {
+            assert_eq!(card.id, original.id);
+            assert_eq!(card.card_type, original.card_type);
+            assert_eq!(card.card_queue, original.card_queue);
+            assert_eq!(card.interval, original.interval);
+            assert_eq!(card.ease_factor, original.ease_factor);
+            assert_eq!(card.learning_state, original.learning_state);
+        }
+        let dues: Vec<i64> = imported.collection.cards.iter().map(|c| c.due).collect();
+        assert_eq!(dues, vec![3, today + 3, today - 2, NOW + 600, today + 4]);
+        // siblings keep their note
+        let cards = &imported.collection.cards;
+        assert_eq!(cards[1].note_id, cards[2].note_id);
+        assert_ne!(cards[0].note_id, cards[3].note_id);
+
+        assert_eq!(imported.review_logs.len(), 2);
+        assert_eq!(imported.review_logs[0].timestamp, NOW - 100);
+        assert_eq!(imported.review_logs[1].choice, Choice::Easy);
+    }
+
+    #[test]
+    fn test_export_error() {
+        let clock = FixedClock::new(NOW, 0);
+        let anki = sample(Config::default(), 0);
+        let apkg = temp_path("missing/export.apkg");
+        assert!(matches!(
+            export_apkg(&apkg, &anki, &clock),
+            Err(Error::Export(_))
+        ));
+    }
+}
@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use rusqlite::{Connection, OpenFlags};
+use serde_json::Value;
+use zip::ZipArchive;
+
+use crate::anki::{temp_collection_path, AnkiCollection};
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue, CardType, LearningState};
+use crate::srs::collection::{Collection, Deck};
+use crate::srs::config::Config;
+use crate::srs::review_log::{ReviewKind, ReviewLog};
+use crate::srs::scheduler::Choice;
+
+fn import_error(err: impl ToString) -> Error {
+    Error::Import(err.to_string())
+}
+
+/// Import a deck package exported from Anki. Packages from Anki 2.1.50 and
+/// later must be exported with "Support older Anki versions" ticked.
+pub fn import_apkg<P: AsRef<Path>>(path: P) -> Result<AnkiCollection, Error> {
+    let mut archive =
+        ZipArchive::new(File::open(path).map_err(import_error)?).map_err(import_error)?;
+    let has = |archive: &ZipArchive<File>, name: &str| archive.file_names().any(|n| n == name);
+    let name = if has(&archive, "collection.anki21") {
+        "collection.anki21"
+    } else if has(&archive, "collection.anki21b") {
+        return Err(Error::Import(
+            "the package is in the newer Anki format; export it with \"Support older Anki \
+             versions\" ticked"
+                .to_string(),
+        ));
+    } else if has(&archive, "collection.anki2") {
+        "collection.anki2"
+    } else {
+        return Err(Error::Import("the package has no collection".to_string()));
+    };
+
+    let temp = temp_collection_path();
+    let extracted = File::create(&temp).and_then(|mut file| {
+        let mut entry = archive.by_name(name).map_err(io::Error::from)?;
+        io::copy(&mut entry, &mut file)
+    });
+    let imported = extracted
+        .map_err(import_error)
+        .and_then(|_| import_collection(&temp));
+    let _ = fs::remove_file(&temp);
+    imported
+}
+
+/// Import a `collection.anki2` file, as found in Anki's profile folder.
+pub fn import_collection<P: AsRef<Path>>(path: P) -> Result<AnkiCollection, Error> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(import_error)?;
+    let (crt, conf, decks, dconf): (i64, String, String, String) = db
+        .query_row("SELECT crt, conf, decks, dconf FROM col", [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .map_err(import_error)?;
+    let conf: Value = serde_json::from_str(&conf).map_err(import_error)?;
+    let decks: Value = serde_json::from_str(&decks).map_err(import_error)?;
+    let dconf: Value = serde_json::from_str(&dconf).map_err(import_error)?;
+
+    let base = collection_config(crt, &conf);
+    let configs = dconf
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(id, options)| Some((id.parse().ok()?, deck_config(&base, options))))
+        .collect();
+
+    let mut collection = Collection {
+        decks: vec![],
+        cards: vec![],
+        unburied_day: 0,
+        configs,
+    };
+    for deck in decks
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(_, deck)| deck)
+    {
+        // filtered decks are left behind, and their cards sent home
+        if deck["dyn"].as_i64().unwrap_or(0) != 0 {
+            continue;
+        }
+        let id = deck["id"]
+            .as_i64()
+            .ok_or_else(|| import_error("deck without id"))?;
+        let name = deck["name"]
+            .as_str()
+            .unwrap_or_default()
+            .replace('\x1f', "::");
+        collection.decks.push(Deck {
+            config_id: Some(deck["conf"].as_i64().unwrap_or(1)),
+            ..Deck::new(id, &name)
+        });
+    }
+    collection.decks.sort_by_key(|deck| deck.id);
+    check_configs(&collection)?;
+    // collections that never switched scheduler don't say which they use
+    let sched_ver = conf["schedVer"].as_i64().unwrap_or(1);
+    collection.cards = read_cards(&db, sched_ver)?;
+
+    Ok(AnkiCollection {
+        collection,
+        review_logs: read_review_logs(&db)?,
+    })
+}
+
+/// Day rollover and creation time from the collection's settings.
+fn collection_config(crt: i64, conf: &Value) -> Config {
+    let defaults = Config::default();
+    let rollover_hour = conf["rollover"]
+        .as_i64()
+        .map_or(defaults.rollover_hour, |hour| hour as i32);
+    let minutes_west = conf["creationOffset"].as_i64().map_or_else(
+        || infer_minutes_west(crt, rollover_hour),
+        |mins| mins as i32,
+    );
+    Config {
+        rollover_hour,
+        collection_created: Some(crt),
+        collection_created_minutes_west: minutes_west,
+        ..defaults
+    }
+}
+
+/// Deck options Anki let through but the schedulers can't use, eg a zero
+/// interval modifier, are reported with the decks using them.
+fn check_configs(collection: &Collection) -> Result<(), Error> {
+    for (id, config) in &collection.configs {
+        let errors = config.validate();
+        if errors.is_empty() {
+            continue;
+        }
+        let decks: Vec<&str> = collection
+            .decks
+            .iter()
+            .filter(|deck| deck.config_id == Some(*id))
+            .map(|deck| deck.name.as_str())
+            .collect();
+        let used_by = if decks.is_empty() {
+            format!("options group {}", id)
+        } else {
+            format!("deck {}", decks.join(", "))
+        };
+        return Err(import_error(format!(
+            "{}: {}",
+            used_by,
+            Error::InvalidConfig(errors).message()
+        )));
+    }
+    Ok(())
+}
+
+/// Older collections don't record their timezone, but `crt` is the rollover
+/// on the day they were made, so the offset is the one putting it at the
+/// rollover hour.
+pub(crate) fn infer_minutes_west(crt: i64, rollover_hour: i32) -> i32 {
+    let utc_minutes = (crt.rem_euclid(86_400) / 60) as i32;
+    let west = (utc_minutes - rollover_hour * 60).rem_euclid(1_440);
+    if west > 720 {
+        west - 1_440
+    } else {
+        west
+    }
+}
+
+fn deck_config(base: &Config, options: &Value) -> Config {
+    let f32_at = |pointer: &str, default: f32| {
+        options
+            .pointer(pointer)
+            .and_then(Value::as_f64)
+            .map_or(default, |value| value as f32)
+    };
+    let i32_at = |pointer: &str, default: i32| {
+        options
+            .pointer(pointer)
+            .and_then(Value::as_i64)
+            .map_or(default, |value| value as i32)
+    };
+    let bool_at = |pointer: &str, default: bool| {
+        options
+            .pointer(pointer)
+            .and_then(Value::as_bool)
+            .unwrap_or(default)
+    };
+    let steps_at = |pointer: &str, default: &[f32]| {
+        options
+            .pointer(pointer)
+            .and_then(Value::as_array)
+            .map_or_else(
+                || default.to_vec(),
+                |steps| {
+                    steps
+                        .iter()
+                        .filter_map(Value::as_f64)
+                        .map(|step| step as f32)
+                        .collect()
+                },
+            )
+    };
+
+    Config {
+        learn_steps: steps_at("/new/delays", &base.learn_steps),
+        relearn_steps: steps_at("/lapse/delays", &base.relearn_steps),
+        initial_ease: i32_at("/new/initialFactor", base.initial_ease),
+        easy_multiplier: f32_at("/rev/ease4", base.easy_multiplier),
+        hard_multiplier: f32_at("/rev/hardFactor", base.hard_multiplier),
+        lapse_multiplier: f32_at("/lapse/mult", base.lapse_multiplier),
+        interval_multiplier: f32_at("/rev/ivlFct", base.interval_multiplier),
+        maximum_review_interval: i32_at("/rev/maxIvl", base.maximum_review_interval),
+        minimum_review_interval: i32_at("/lapse/minInt", base.minimum_review_interval),
+        graduating_interval_good: i32_at("/new/ints/0", base.graduating_interval_good),
+        graduating_interval_easy: i32_at("/new/ints/1", base.graduating_interval_easy),
+        leech_threshold: i32_at("/lapse/leechFails", base.leech_threshold),
+        new_per_day: i32_at("/new/perDay", base.new_per_day),
+        reviews_per_day: i32_at("/rev/perDay", base.reviews_per_day),
+        bury_new: bool_at("/new/bury", base.bury_new),
+        bury_reviews: bool_at("/rev/bury", base.bury_reviews),
+        bury_interday_learning: bool_at("/buryInterdayLearning", base.bury_interday_learning),
+        ..base.clone()
+    }
+}
+
+fn card_type(value: i64) -> Result<CardType, Error> {
+    Ok(match value {
+        0 => CardType::New,
+        1 => CardType::Learn,
+        2 => CardType::Review,
+        3 => CardType::Relearn,
+        _ => return Err(Error::Import(format!("unknown card type {}", value))),
+    })
+}
+
+fn card_queue(value: i64) -> Result<CardQueue, Error> {
+    Ok(match value {
+        0 => CardQueue::New,
+        1 => CardQueue::Learn,
+        2 => CardQueue::Review,
+        3 => CardQueue::DayLearn,
+        4 => CardQueue::Preview,
+        -1 => CardQueue::Suspended,
+        // buried with a sibling, or by hand
+        -2 | -3 => CardQueue::Buried,
+        _ => return Err(Error::Import(format!("unknown card queue {}", value))),
+    })
+}
+
+fn read_cards(db: &Connection, sched_ver: i64) -> Result<Vec<Card>, Error> {
+    let mut stmt = db
+        .prepare(
+            "SELECT id, nid, did, type, queue, due, ivl, factor, reps, lapses, left, odue, odid
+             FROM cards ORDER BY id",
+        )
+        .map_err(import_error)?;
+    let rows = stmt
+        .query_map([], |row| {
+            let mut columns = [0i64; 13];
+            for (i, column) in columns.iter_mut().enumerate() {
+                *column = row.get(i)?;
+            }
+            Ok(columns)
+        })
+        .map_err(import_error)?;
+
+    let mut cards = vec![];
+    for row in rows {
+        let [id, nid, did, ctype, queue, due, ivl, factor, reps, lapses, left, odue, odid] =
+            row.map_err(import_error)?;
+        let mut card_type = card_type(ctype)?;
+        // the v1 scheduler keeps relearning cards as reviews in the learning
+        // queues, which Anki's upgrade to v2 turns into relearning cards
+        if sched_ver == 1 && card_type == CardType::Review && (queue == 1 || queue == 3) {
+            card_type = CardType::Relearn;
+        }
+        let learning_state = match card_type {
+            CardType::Learn | CardType::Relearn => {
+                LearningState::from_packed(left as i32, card_type)
+            }
+            _ => None,
+        };
+        let mut card = Card {
+            id,
+            note_id: nid,
+            deck_id: did,
+            card_type,
+            card_queue: card_queue(queue)?,
+            due,
+            interval: ivl as i32,
+            ease_factor: factor as i32,
+            reps: reps as i32,
+            lapses: lapses as i32,
+            learning_state,
+            ..Card::default()
+        };
+        if odid != 0 {
+            return_home(&mut card, odid, odue);
+        }
+        card.validate()
+            .map_err(|e| Error::Import(format!("card {}: {}", id, e.message())))?;
+        cards.push(card);
+    }
+    Ok(cards)
+}
+
+/// Move a card out of the filtered deck it's in. Intraday learning keeps
+/// its due time, like in Anki.
+fn return_home(card: &mut Card, odid: i64, odue: i64) {
+    card.deck_id = odid;
+    if card.card_queue != CardQueue::Learn && odue != 0 {
+        card.due = odue;
+    }
+    if card.card_queue == CardQueue::Preview {
+        card.card_queue = card.queue_for_type();
+    }
+}
+
+fn read_review_logs(db: &Connection) -> Result<Vec<ReviewLog>, Error> {
+    let mut stmt = db
+        .prepare("SELECT id, cid, ease, ivl, lastIvl, factor, time, type FROM revlog ORDER BY id")
+        .map_err(import_error)?;
+    let rows = stmt
+        .query_map([], |row| {
+            let mut columns = [0i64; 8];
+            for (i, column) in columns.iter_mut().enumerate() {
+                *column = row.get(i)?;
+            }
+            Ok(columns)
+        })
+        .map_err(import_error)?;
+
+    // the ease each card was left with by its previous review
+    let mut last_ease: HashMap<i64, i32> = HashMap::new();
+    let mut logs = vec![];
+    for row in rows {
+        let [id, cid, ease, ivl, last_ivl, factor, time, kind] = row.map_err(import_error)?;
+        let choice = match ease {
+            1 => Choice::Again,
+            2 => Choice::Hard,
+            3 => Choice::Ok,
+            4 => Choice::Easy,
+            // rescheduled by hand rather than answered
+            _ => continue,
+        };
+        let review_kind = match kind {
+            0 => ReviewKind::Learn,
+            1 => ReviewKind::Review,
+            2 => ReviewKind::Relearn,
+            3 => ReviewKind::Filtered,
+            _ => continue,
+        };
+        let last_ease_factor = last_ease.insert(cid, factor as i32);
+        let card_type = match review_kind {
+            ReviewKind::Learn if last_ease_factor.is_none() => CardType::New,
+            ReviewKind::Learn => CardType::Learn,
+            ReviewKind::Relearn => CardType::Relearn,
+            ReviewKind::Review | ReviewKind::Filtered => CardType::Review,
+        };
+        // negative intervals are learning steps in seconds
+        logs.push(ReviewLog {
+            card_id: cid,
+            timestamp: id / 1_000,
+            choice,
+            interval: ivl.max(0) as i32,
+            last_interval: last_ivl.max(0) as i32,
+            ease_factor: factor as i32,
+            last_ease_factor: last_ease_factor.unwrap_or(0),
+            card_type,
+            time_taken: time as i32,
+            review_kind,
+        });
+    }
+    Ok(logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde_json::json;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use crate::anki::SCHEMA;
+    use crate::srs::card::StepKind;
+    use crate::svc::clock::FixedClock;
+
+    use super::*;
+
+    // 2021-03-01 04:00 at UTC+10
+    const CRT: i64 = 1_614_535_200;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("sm2-test-{}-{}", std::process::id(), name))
+    }
+
+    /// A collection in Anki's schema 11.
+    fn anki_collection(path: &Path) {
+        let _ = fs::remove_file(path);
+        let db = Connection::open(path).unwrap();
+        db.execute_batch(SCHEMA).unwrap();
+
+        let decks = json!({
+            "1": {"id": 1, "name": "Default", "conf": 1, "dyn": 0},
+            "1500": {"id": 1500, "name": "Japanese\u{1f}Vocab", "conf": 2, "dyn": 0},
+            "2000": {"id": 2000, "name": "Cram", "dyn": 1},
+        });
+        let dconf = json!({
+            "1": {"id": 1, "new": {"delays": [1.0, 10.0]}},
+            "2": {
+                "id": 2,
+                "new": {"delays": [2.0, 20.0], "ints": [2, 5, 0], "initialFactor": 2300,
+                        "perDay": 10, "bury": true},
+                "lapse": {"delays": [15.0], "mult": 0.5, "minInt": 2, "leechFails": 6},
+                "rev": {"perDay": 100, "ease4": 1.4, "ivlFct": 1.1, "maxIvl": 3650,
+                        "hardFactor": 1.3, "bury": false},
+            },
+        });
+        db.execute(
+            "INSERT INTO col VALUES (1, ?1, 0, 0, 11, 0, 0, 0, ?2, '{}', ?3, ?4, '{}')",
+            rusqlite::params![
+                CRT,
+                // no schedVer, as in collections still on the v1 scheduler
+                json!({"rollover": 4}).to_string(),
+                decks.to_string(),
+                dconf.to_string()
+            ],
+        )
+        .unwrap();
+
+        // id, did, type, queue, due, ivl, factor, reps, lapses, left, odue, odid
+        let cards: &[[i64; 12]] = &[
+            [1, 1, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0],
+            [2, 1500, 1, 1, 1_614_600_600, 0, 0, 1, 0, 2_002, 0, 0],
+            [3, 1500, 2, 2, 30, 10, 2_500, 5, 0, 0, 0, 0],
+            [4, 1500, 3, 3, 31, 1, 2_300, 9, 1, 1_001, 0, 0],
+            [5, 2000, 2, 2, 29, 8, 2_500, 4, 0, 0, 40, 1500],
+            // buried by hand
+            [6, 1, 2, -3, 35, 20, 2_500, 4, 0, 0, 0, 0],
+            // relearning, as the v1 scheduler stores it
+            [7, 1500, 2, 1, 1_614_600_900, 1, 2_300, 7, 2, 1_001, 0, 0],
+        ];
+        for card in cards {
+            db.execute(
+                "INSERT INTO cards VALUES (?1, 100, ?2, 0, 0, 0, ?3, ?4, ?5, ?6, ?7, ?8, ?9,
+                 ?10, ?11, ?12, 0, '')",
+                rusqlite::params![
+                    card[0], card[1], card[2], card[3], card[4], card[5], card[6], card[7],
+                    card[8], card[9], card[10], card[11]
+                ],
+            )
+            .unwrap();
+        }
+
+        // id, cid, ease, ivl, lastIvl, factor, time, type
+        let revlog: &[[i64; 8]] = &[
+            [1_614_000_000_000, 3, 3, -600, -60, 2_500, 5_000, 0],
+            [1_614_100_000_000, 3, 3, 4, -600, 2_500, 4_000, 0],
+            [1_614_500_000_000, 3, 4, 10, 4, 2_650, 3_000, 1],
+            [1_614_600_000_000, 3, 0, 20, 10, 2_650, 0, 4],
+        ];
+        for log in revlog {
+            db.execute(
+                "INSERT INTO revlog VALUES (?1, ?2, 0, ?3, ?4, ?5, ?6, ?7, ?8)",
+                rusqlite::params![log[0], log[1], log[2], log[3], log[4], log[5], log[6], log[7]],
+            )
+            .unwrap();
+        }
+    }
+
+    fn package(path: &Path, entries: &[(&str, &Path)]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, source) in entries {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(&fs::read(source).unwrap()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_import_apkg() {
+        let anki2 = temp_path("collection.anki2");
+        let apkg = temp_path("deck.apkg");
+        anki_collection(&anki2);
+        package(&apkg, &[("collection.anki2", &anki2), ("media", &anki2)]);
+        let imported = import_apkg(&apkg).unwrap();
+
+        let names: Vec<&str> = imported
+            .collection
+            .decks
+            .iter()
+            .map(|deck| deck.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Default", "Japanese::Vocab"]);
+        assert_eq!(imported.collection.decks[1].config_id, Some(2));
+
+        let config = &imported.collection.configs[&2];
+        assert_eq!(config.learn_steps, vec![2.0, 20.0]);
+        assert_eq!(config.relearn_steps, vec![15.0]);
+        assert_eq!(config.initial_ease, 2_300);
+        assert_eq!(config.graduating_interval_good, 2);
+        assert_eq!(config.graduating_interval_easy, 5);
+        assert_eq!(config.new_per_day, 10);
+        assert_eq!(config.reviews_per_day, 100);
+        assert_eq!(config.leech_threshold, 6);
+        assert_eq!(config.maximum_review_interval, 3_650);
+        assert!(config.bury_new);
+        assert!(config.validate().is_empty());
+        assert_eq!(imported.collection.configs[&1].learn_steps, vec![1.0, 10.0]);
+
+        // day numbers carry on from Anki's: 2021-03-31 12:00 at UTC+10 is
+        // day 30
+        assert_eq!(config.collection_created, Some(CRT));
+        assert_eq!(config.collection_created_minutes_west, -600);
+        let clock = FixedClock::new(1_617_156_000, -600);
+        assert_eq!(config.day_today(&clock), 30);
+
+        let cards = &imported.collection.cards;
+        assert_eq!(cards.len(), 7);
+        assert_eq!(cards[0].card_queue, CardQueue::New);
+        assert_eq!(cards[0].due, 5);
+        assert_eq!(
+            cards[1].learning_state,
+            Some(LearningState {
+                kind: StepKind::Learn,
+                remaining: 2,
+                remaining_today: 2,
+            })
+        );
+        assert_eq!(cards[2].interval, 10);
+        assert_eq!(cards[2].ease_factor, 2_500);
+        assert_eq!(cards[2].note_id, 100);
+        assert_eq!(cards[3].card_queue, CardQueue::DayLearn);
+        assert_eq!(cards[3].learning_state.unwrap().kind, StepKind::Relearn);
+        // back home from the filtered deck
+        assert_eq!(cards[4].deck_id, 1500);
+        assert_eq!(cards[4].due, 40);
+        assert_eq!(cards[5].card_queue, CardQueue::Buried);
+        assert_eq!(cards[6].card_type, CardType::Relearn);
+        assert_eq!(cards[6].card_queue, CardQueue::Learn);
+        assert_eq!(
+            cards[6].learning_state,
+            Some(LearningState {
+                kind: StepKind::Relearn,
+                remaining: 1,
+                remaining_today: 1,
+            })
+        );
+
+        let logs = &imported.review_logs;
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[0].card_type, CardType::New);
+        assert_eq!(logs[0].interval, 0);
+        assert_eq!(logs[0].timestamp, 1_614_000_000);
+        assert_eq!(logs[1].card_type, CardType::Learn);
+        assert_eq!(logs[2].choice, Choice::Easy);
+        assert_eq!(logs[2].review_kind, ReviewKind::Review);
+        assert_eq!(logs[2].last_interval, 4);
+        assert_eq!(logs[2].last_ease_factor, 2_500);
+
+        fs::remove_file(&anki2).unwrap();
+        fs::remove_file(&apkg).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let anki2 = temp_path("invalid.anki2");
+        anki_collection(&anki2);
+        let db = Connection::open(&anki2).unwrap();
+        let dconf = json!({
+            "1": {"id": 1},
+            "2": {"id": 2, "rev": {"ivlFct": 0.0}, "lapse": {"delays": [-1.0]}},
+        });
+        db.execute("UPDATE col SET dconf = ?1", [dconf.to_string()])
+            .unwrap();
+        drop(db);
+
+        match import_collection(&anki2) {
+            Err(Error::Import(message)) => assert_eq!(
+                message,
+                "deck Japanese::Vocab: relearn_steps must all be greater than 0, \
+                 interval_multiplier must be greater than 0"
+            ),
+            _ => panic!("expected an import error"),
+        }
+        fs::remove_file(&anki2).unwrap();
+    }
+
+    #[test]
+    fn test_newer_packages() {
+        let anki2 = temp_path("newer.anki2");
+        let apkg = temp_path("newer.apkg");
+        anki_collection(&anki2);
+        package(
+            &apkg,
+            &[("collection.anki2", &anki2), ("collection.anki21b", &anki2)],
+        );
+        assert!(matches!(import_apkg(&apkg), Err(Error::Import(_))));
+
+        package(&apkg, &[("media", &anki2)]);
+        assert!(matches!(import_apkg(&apkg), Err(Error::Import(_))));
+
+        fs::remove_file(&anki2).unwrap();
+        fs::remove_file(&apkg).unwrap();
+    }
+
+    #[test]
+    fn test_infer_minutes_west() {
+        assert_eq!(infer_minutes_west(CRT, 4), -600);
+        // 04:00 at UTC-5
+        assert_eq!(infer_minutes_west(1_614_589_200, 4), 300);
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::srs::collection::Collection;
+use crate::srs::review_log::ReviewLog;
+
+pub mod export;
+pub mod import;
+
+/// A collection with everything Anki keeps alongside it. The collection's
+/// `configs` are Anki's deck options, with its rollover hour and creation
+/// time, so due days keep counting from the same day as in Anki.
+pub struct AnkiCollection {
+    pub collection: Collection,
+    pub review_logs: Vec<ReviewLog>,
+}
+
+// Anki's schema 11, used by `collection.anki2` and `collection.anki21`
+pub(crate) const SCHEMA: &str = "
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null,
+    scm integer not null, ver integer not null, dty integer not null,
+    usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null,
+    mod integer not null, usn integer not null, tags text not null,
+    flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null,
+    ord integer not null, mod integer not null, usn integer not null,
+    type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null,
+    odid integer not null, flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null,
+    ease integer not null, ivl integer not null, lastIvl integer not null,
+    factor integer not null, time integer not null, type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn ON notes (usn);
+CREATE INDEX ix_cards_usn ON cards (usn);
+CREATE INDEX ix_revlog_usn ON revlog (usn);
+CREATE INDEX ix_cards_nid ON cards (nid);
+CREATE INDEX ix_cards_sched ON cards (did, queue, due);
+CREATE INDEX ix_revlog_cid ON revlog (cid);
+CREATE INDEX ix_notes_csum ON notes (csum);
+";
+
+// keeps the temporary files of imports and exports running at the same time
+// apart
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Somewhere to put a collection while it's zipped or unzipped.
+pub(crate) fn temp_collection_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "sm2-{}-{}.anki2",
+        process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ))
+}
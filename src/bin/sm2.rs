@@ -0,0 +1,271 @@
+//! Review a collection saved as JSON from the terminal.
+//!
+//! ```shell
+//! sm2 review collection.json [--config config.json] [--deck 1] [--log reviews.jsonl]
+//! sm2 due collection.json [--config config.json] [--deck 1]
+//! ```
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+
+use sm2::error::{ConfigError, Error};
+use sm2::srs::card::Card;
+use sm2::srs::collection::{Collection, DEFAULT_DECK_ID};
+use sm2::srs::config::Config;
+use sm2::srs::registry::Registry;
+use sm2::srs::scheduler::{Choice, Sched};
+use sm2::svc::clock::SystemClock;
+
+const USAGE: &str = "usage: sm2 <review|due> <collection.json> [--config <config.json>] \
+                     [--deck <id>] [--log <reviews.jsonl>]";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Review,
+    Due,
+}
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    command: Command,
+    collection: PathBuf,
+    config: Option<PathBuf>,
+    deck_id: i64,
+    log: Option<PathBuf>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let command = match args.next().as_deref() {
+        Some("review") => Command::Review,
+        Some("due") => Command::Due,
+        Some(other) => return Err(format!("unknown command {}", other)),
+        None => return Err("missing command".to_string()),
+    };
+    let collection = args.next().ok_or("missing collection file")?.into();
+
+    let mut parsed = Args {
+        command,
+        collection,
+        config: None,
+        deck_id: DEFAULT_DECK_ID,
+        log: None,
+    };
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", flag))?;
+        match flag.as_str() {
+            "--config" => parsed.config = Some(value.into()),
+            "--deck" => {
+                parsed.deck_id = value
+                    .parse()
+                    .map_err(|_| format!("{} is not a deck id", value))?
+            }
+            "--log" => parsed.log = Some(value.into()),
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Read an answer typed as a button number or the first letter of its name.
+fn parse_choice(input: &str) -> Option<Choice> {
+    match input.trim().to_lowercase().as_str() {
+        "1" | "a" | "again" => Some(Choice::Again),
+        "2" | "h" | "hard" => Some(Choice::Hard),
+        "3" | "o" | "ok" => Some(Choice::Ok),
+        "4" | "e" | "easy" => Some(Choice::Easy),
+        _ => None,
+    }
+}
+
+fn load_config(path: Option<&Path>) -> Result<Config, String> {
+    let config: Config = match path {
+        Some(path) => serde_json::from_str(&read(path)?).map_err(|e| {
+            Error::InvalidConfig(vec![ConfigError::new("config", e.to_string())]).to_string()
+        })?,
+        None => Config::default(),
+    };
+    config.validated().map_err(|e| e.to_string())
+}
+
+fn load_collection(path: &Path) -> Result<Collection, String> {
+    let collection: Collection = serde_json::from_str(&read(path)?)
+        .map_err(|e| Error::InvalidCard(e.to_string()).to_string())?;
+    for card in &collection.cards {
+        card.validate().map_err(|e| e.to_string())?;
+    }
+    Ok(collection)
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Write through a temporary file, so an interrupted save keeps the old
+/// collection.
+fn save_collection(path: &Path, collection: &Collection) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(collection).map_err(|e| e.to_string())?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json)
+        .and_then(|_| fs::rename(&temp, path))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn describe(card: &Card) -> String {
+    format!(
+        "card {} (note {}): {:?} in {:?} queue, due {}, interval {}d, ease {}, reps {}, lapses {}",
+        card.id,
+        card.note_id,
+        card.card_type,
+        card.card_queue,
+        card.due,
+        card.interval,
+        card.ease_factor,
+        card.reps,
+        card.lapses
+    )
+}
+
+fn due(args: &Args, sched: &dyn Sched, config: &Config) -> Result<(), String> {
+    let mut collection = load_collection(&args.collection)?;
+    let queues = collection.build_queues(args.deck_id, sched, config);
+    println!("learning:       {}", queues.learn.len());
+    println!("review:         {}", queues.review.len());
+    println!("interday learn: {}", queues.day_learn.len());
+    println!("new:            {}", queues.new.len());
+    println!("learn ahead:    {}", queues.learn_ahead.len());
+    Ok(())
+}
+
+fn review(args: &Args, sched: &dyn Sched, config: &Config) -> Result<(), String> {
+    let mut collection = load_collection(&args.collection)?;
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        let card = match collection.get_next_card(args.deck_id, sched, config) {
+            Some(card) => card.clone(),
+            None => {
+                println!("Nothing left to study today.");
+                return Ok(());
+            }
+        };
+
+        println!("\n{}", describe(&card));
+        let buttons: Vec<String> = [Choice::Again, Choice::Hard, Choice::Ok, Choice::Easy]
+            .iter()
+            .map(|choice| {
+                format!(
+                    "{} {:?} ({})",
+                    *choice as i8,
+                    choice,
+                    sched.next_interval_string(&card, *choice)
+                )
+            })
+            .collect();
+        println!("{}", buttons.join("  "));
+
+        let shown = Instant::now();
+        let choice = loop {
+            print!("answer, or q to quit: ");
+            io::stdout().flush().map_err(|e| e.to_string())?;
+            let line = match lines.next() {
+                Some(line) => line.map_err(|e| e.to_string())?,
+                None => return Ok(()),
+            };
+            if line.trim() == "q" {
+                return Ok(());
+            }
+            if let Some(choice) = parse_choice(&line) {
+                break choice;
+            }
+        };
+        let time_taken = shown.elapsed().as_millis().min(i32::MAX as u128) as i32;
+
+        let log = collection
+            .answer_card(card.id, choice, time_taken, sched, config)
+            .map_err(|e| e.to_string())?;
+        save_collection(&args.collection, &collection)?;
+        if let Some(path) = &args.log {
+            append_log(
+                path,
+                &serde_json::to_string(&log).map_err(|e| e.to_string())?,
+            )?;
+        }
+        if let Some(card) = collection.card(card.id) {
+            println!("{}", describe(card));
+        }
+    }
+}
+
+fn append_log(path: &Path, line: &str) -> Result<(), String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn run() -> Result<(), String> {
+    let args = parse_args(env::args().skip(1)).map_err(|e| format!("{}\n{}", e, USAGE))?;
+    let config = load_config(args.config.as_deref())?;
+    let sched = Registry::default()
+        .build(config.clone(), Box::new(SystemClock))
+        .map_err(|e| e.to_string())?;
+
+    match args.command {
+        Command::Review => review(&args, sched.as_ref(), &config),
+        Command::Due => due(&args, sched.as_ref(), &config),
+    }
+}
+
+fn main() {
+    if let Err(message) = run() {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Args, String> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            args("review deck.json --deck 3 --log log.jsonl"),
+            Ok(Args {
+                command: Command::Review,
+                collection: "deck.json".into(),
+                config: None,
+                deck_id: 3,
+                log: Some("log.jsonl".into()),
+            })
+        );
+        assert_eq!(args("due deck.json").unwrap().deck_id, DEFAULT_DECK_ID);
+        assert!(args("").is_err());
+        assert!(args("study deck.json").is_err());
+        assert!(args("review").is_err());
+        assert!(args("review deck.json --deck").is_err());
+        assert!(args("review deck.json --deck one").is_err());
+    }
+
+    #[test]
+    fn test_parse_choice() {
+        assert_eq!(parse_choice("1\n"), Some(Choice::Again));
+        assert_eq!(parse_choice("H"), Some(Choice::Hard));
+        assert_eq!(parse_choice("ok"), Some(Choice::Ok));
+        assert_eq!(parse_choice("4"), Some(Choice::Easy));
+        assert_eq!(parse_choice("5"), None);
+    }
+}
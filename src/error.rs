@@ -0,0 +1,106 @@
+use std::fmt;
+
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+/// Errors surfaced to JavaScript as `{ kind, message }` objects. Config
+/// errors also carry a `fields` list so each offending option can be flagged.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The card could not be read
+    InvalidCard(String),
+    /// The answer is not one of Again/Hard/Ok/Easy
+    InvalidChoice(String),
+    /// The config could not be read or has unusable values
+    InvalidConfig(Vec<ConfigError>),
+    /// The card was read, but its fields contradict each other
+    InconsistentCard(String),
+    /// A review log could not be read
+    InvalidReviewLog(String),
+    /// The deck doesn't exist or can't be used that way
+    InvalidDeck(String),
+    /// Reading or writing stored cards failed
+    Storage(String),
+    /// A file from another program could not be imported
+    Import(String),
+    /// A file for another program could not be written
+    Export(String),
+    /// Any other argument could not be read
+    InvalidInput(String),
+}
+
+/// A single config option that failed validation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigError {
+    pub field: String,
+    pub reason: String,
+}
+
+impl ConfigError {
+    pub fn new(field: &str, reason: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::InvalidCard(_) => "invalid_card",
+            Error::InvalidChoice(_) => "invalid_choice",
+            Error::InvalidConfig(_) => "invalid_config",
+            Error::InconsistentCard(_) => "inconsistent_card",
+            Error::InvalidReviewLog(_) => "invalid_review_log",
+            Error::InvalidDeck(_) => "invalid_deck",
+            Error::Storage(_) => "storage",
+            Error::Import(_) => "import",
+            Error::Export(_) => "export",
+            Error::InvalidInput(_) => "invalid_input",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Error::InvalidCard(msg)
+            | Error::InvalidChoice(msg)
+            | Error::InconsistentCard(msg)
+            | Error::InvalidReviewLog(msg)
+            | Error::InvalidDeck(msg)
+            | Error::Storage(msg)
+            | Error::Import(msg)
+            | Error::Export(msg)
+            | Error::InvalidInput(msg) => msg.clone(),
+            Error::InvalidConfig(errors) => errors
+                .iter()
+                .map(|e| format!("{} {}", e.field, e.reason))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind().replace('_', " "), self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = match self {
+            Error::InvalidConfig(errors) => Some(errors),
+            _ => None,
+        };
+        let mut state = serializer.serialize_struct("Error", 2 + fields.is_some() as usize)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.message())?;
+        if let Some(fields) = fields {
+            state.serialize_field("fields", fields)?;
+        }
+        state.end()
+    }
+}
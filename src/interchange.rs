@@ -0,0 +1,667 @@
+//! Cards and review logs as CSV or JSON Lines, for spreadsheets and bulk
+//! repairs.
+//!
+//! JSON Lines files start with `{"format":"sm2","version":1}`, followed by
+//! one `{"record":"card",...}` or `{"record":"review_log",...}` object per
+//! line, with the fields `Card` and `ReviewLog` have in the JavaScript API.
+//!
+//! CSV files hold either cards or review logs, with a header row and a
+//! `version` column. Enums are written as names, eg `day_learn`, and read as
+//! names or numbers. Empty cells leave optional fields unset.
+
+use std::io::{BufRead, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue, CardType, LearningState, MemoryState, StepKind};
+use crate::srs::collection::DEFAULT_DECK_ID;
+use crate::srs::review_log::{ReviewKind, ReviewLog};
+use crate::srs::scheduler::Choice;
+
+/// Bumped when a file written by this version can't be read by older ones.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Cards and review logs read from a file.
+#[derive(Default)]
+pub struct Records {
+    pub cards: Vec<Card>,
+    pub review_logs: Vec<ReviewLog>,
+}
+
+fn read_error(line: usize, err: impl ToString) -> Error {
+    Error::Import(format!("line {}: {}", line, err.to_string()))
+}
+
+fn write_error(err: impl ToString) -> Error {
+    Error::Export(err.to_string())
+}
+
+fn check_version(line: usize, version: u32) -> Result<(), Error> {
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(read_error(
+            line,
+            format!("version {} is not supported", version),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum Record {
+    Card(Card),
+    ReviewLog(ReviewLog),
+}
+
+fn write_line<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<(), Error> {
+    serde_json::to_writer(&mut *writer, value).map_err(write_error)?;
+    writer.write_all(b"\n").map_err(write_error)
+}
+
+pub fn write_jsonl<W: Write>(
+    mut writer: W,
+    cards: &[Card],
+    review_logs: &[ReviewLog],
+) -> Result<(), Error> {
+    let header = Header {
+        format: "sm2".to_string(),
+        version: FORMAT_VERSION,
+    };
+    write_line(&mut writer, &header)?;
+    for card in cards {
+        write_line(&mut writer, &Record::Card(card.clone()))?;
+    }
+    for log in review_logs {
+        write_line(&mut writer, &Record::ReviewLog(log.clone()))?;
+    }
+    Ok(())
+}
+
+/// Read a JSON Lines file, checking every card is consistent.
+pub fn read_jsonl<R: BufRead>(reader: R) -> Result<Records, Error> {
+    let mut lines = reader.lines().enumerate().map(|(i, line)| (i + 1, line));
+    let header: Header = match lines.next() {
+        Some((n, line)) => serde_json::from_str(&line.map_err(|e| read_error(n, e))?)
+            .map_err(|e| read_error(n, format!("not an sm2 header: {}", e)))?,
+        None => return Err(read_error(1, "the file is empty")),
+    };
+    if header.format != "sm2" {
+        return Err(read_error(1, format!("unknown format {}", header.format)));
+    }
+    check_version(1, header.version)?;
+
+    let mut records = Records::default();
+    for (n, line) in lines {
+        let line = line.map_err(|e| read_error(n, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line).map_err(|e| read_error(n, e))? {
+            Record::Card(card) => {
+                card.validate().map_err(|e| read_error(n, e.message()))?;
+                records.cards.push(card);
+            }
+            Record::ReviewLog(log) => records.review_logs.push(log),
+        }
+    }
+    Ok(records)
+}
+
+fn card_type_name(card_type: CardType) -> &'static str {
+    match card_type {
+        CardType::New => "new",
+        CardType::Learn => "learn",
+        CardType::Review => "review",
+        CardType::Relearn => "relearn",
+    }
+}
+
+fn card_queue_name(queue: CardQueue) -> &'static str {
+    match queue {
+        CardQueue::New => "new",
+        CardQueue::Learn => "learn",
+        CardQueue::Review => "review",
+        CardQueue::DayLearn => "day_learn",
+        CardQueue::Preview => "preview",
+        CardQueue::Suspended => "suspended",
+        CardQueue::Buried => "buried",
+    }
+}
+
+fn step_kind_name(kind: StepKind) -> &'static str {
+    match kind {
+        StepKind::Learn => "learn",
+        StepKind::Relearn => "relearn",
+    }
+}
+
+fn choice_name(choice: Choice) -> &'static str {
+    match choice {
+        Choice::Again => "again",
+        Choice::Hard => "hard",
+        Choice::Ok => "ok",
+        Choice::Easy => "easy",
+    }
+}
+
+fn review_kind_name(kind: ReviewKind) -> &'static str {
+    match kind {
+        ReviewKind::Learn => "learn",
+        ReviewKind::Review => "review",
+        ReviewKind::Relearn => "relearn",
+        ReviewKind::Filtered => "filtered",
+    }
+}
+
+/// Read an enum from its name or number, the names being listed with
+/// their values in declaration order.
+fn parse_enum<T: Copy>(
+    field: &str,
+    value: &str,
+    variants: &[T],
+    name: fn(T) -> &'static str,
+    number: fn(T) -> i8,
+) -> Result<T, String> {
+    let value = value.trim();
+    variants
+        .iter()
+        .copied()
+        .find(|variant| {
+            name(*variant).eq_ignore_ascii_case(value) || number(*variant).to_string() == value
+        })
+        .ok_or_else(|| {
+            let names: Vec<&str> = variants.iter().map(|v| name(*v)).collect();
+            format!("{} {:?} is not one of {}", field, value, names.join(", "))
+        })
+}
+
+fn parse_card_type(value: &str) -> Result<CardType, String> {
+    use CardType::*;
+    parse_enum(
+        "card_type",
+        value,
+        &[New, Learn, Review, Relearn],
+        card_type_name,
+        |v| v as i8,
+    )
+}
+
+fn parse_card_queue(field: &str, value: &str) -> Result<CardQueue, String> {
+    use CardQueue::*;
+    parse_enum(
+        field,
+        value,
+        &[New, Learn, Review, DayLearn, Preview, Suspended, Buried],
+        card_queue_name,
+        |v| v as i8,
+    )
+}
+
+fn parse_step_kind(value: &str) -> Result<StepKind, String> {
+    use StepKind::*;
+    parse_enum(
+        "learning_kind",
+        value,
+        &[Learn, Relearn],
+        step_kind_name,
+        |v| v as i8,
+    )
+}
+
+fn parse_choice(value: &str) -> Result<Choice, String> {
+    use Choice::*;
+    parse_enum(
+        "choice",
+        value,
+        &[Again, Hard, Ok, Easy],
+        choice_name,
+        |v| v as i8,
+    )
+}
+
+fn parse_review_kind(value: &str) -> Result<ReviewKind, String> {
+    use ReviewKind::*;
+    parse_enum(
+        "review_kind",
+        value,
+        &[Learn, Review, Relearn, Filtered],
+        review_kind_name,
+        |v| v as i8,
+    )
+}
+
+/// A card flattened into CSV columns.
+#[derive(Serialize, Deserialize)]
+struct CardRow {
+    version: u32,
+    id: i64,
+    #[serde(default)]
+    note_id: Option<i64>,
+    #[serde(default)]
+    deck_id: Option<i64>,
+    card_type: String,
+    card_queue: String,
+    due: i64,
+    interval: i32,
+    ease_factor: i32,
+    reps: i32,
+    lapses: i32,
+    #[serde(default)]
+    learning_kind: Option<String>,
+    #[serde(default)]
+    learning_remaining: Option<u32>,
+    #[serde(default)]
+    learning_remaining_today: Option<u32>,
+    #[serde(default)]
+    original_deck_id: Option<i64>,
+    #[serde(default)]
+    original_due: Option<i64>,
+    #[serde(default)]
+    original_queue: Option<String>,
+    #[serde(default)]
+    buried_day: Option<i64>,
+    #[serde(default)]
+    stability: Option<f32>,
+    #[serde(default)]
+    difficulty: Option<f32>,
+    // JSON
+    #[serde(default)]
+    scheduler_state: Option<String>,
+}
+
+impl CardRow {
+    fn new(card: &Card) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            id: card.id,
+            note_id: Some(card.note_id),
+            deck_id: Some(card.deck_id),
+            card_type: card_type_name(card.card_type).to_string(),
+            card_queue: card_queue_name(card.card_queue).to_string(),
+            due: card.due,
+            interval: card.interval,
+            ease_factor: card.ease_factor,
+            reps: card.reps,
+            lapses: card.lapses,
+            learning_kind: card
+                .learning_state
+                .map(|state| step_kind_name(state.kind).to_string()),
+            learning_remaining: card.learning_state.map(|state| state.remaining),
+            learning_remaining_today: card.learning_state.map(|state| state.remaining_today),
+            original_deck_id: card.original_deck_id,
+            original_due: card.original_due,
+            original_queue: card
+                .original_queue
+                .map(|queue| card_queue_name(queue).to_string()),
+            buried_day: card.buried_day,
+            stability: card.memory_state.map(|memory| memory.stability),
+            difficulty: card.memory_state.map(|memory| memory.difficulty),
+            scheduler_state: card.scheduler_state.as_ref().map(Value::to_string),
+        }
+    }
+
+    fn into_card(self) -> Result<Card, String> {
+        let learning_state = match (self.learning_kind, self.learning_remaining) {
+            (None, None) => None,
+            (kind, Some(remaining)) => Some(LearningState {
+                kind: match kind.as_deref() {
+                    Some(kind) => parse_step_kind(kind)?,
+                    None => StepKind::for_card_type(parse_card_type(&self.card_type)?),
+                },
+                remaining,
+                remaining_today: self.learning_remaining_today.unwrap_or(remaining),
+            }),
+            (Some(_), None) => return Err("learning_kind needs learning_remaining".to_string()),
+        };
+        let memory_state = match (self.stability, self.difficulty) {
+            (Some(stability), Some(difficulty)) => Some(MemoryState {
+                stability,
+                difficulty,
+            }),
+            (None, None) => None,
+            _ => return Err("stability and difficulty go together".to_string()),
+        };
+        let scheduler_state = self
+            .scheduler_state
+            .map(|state| serde_json::from_str(&state))
+            .transpose()
+            .map_err(|e| format!("scheduler_state: {}", e))?;
+
+        let mut card = Card {
+            id: self.id,
+            note_id: self.note_id.unwrap_or(0),
+            deck_id: self.deck_id.unwrap_or(DEFAULT_DECK_ID),
+            original_deck_id: self.original_deck_id,
+            original_due: self.original_due,
+            card_type: parse_card_type(&self.card_type)?,
+            card_queue: parse_card_queue("card_queue", &self.card_queue)?,
+            due: self.due,
+            interval: self.interval,
+            ease_factor: self.ease_factor,
+            reps: self.reps,
+            lapses: self.lapses,
+            learning_state,
+            original_queue: self
+                .original_queue
+                .as_deref()
+                .map(|queue| parse_card_queue("original_queue", queue))
+                .transpose()?,
+            buried_day: self.buried_day,
+            memory_state,
+            scheduler_state,
+        };
+        card.fix_step_kind();
+        card.validate().map_err(|e| e.message())?;
+        Ok(card)
+    }
+}
+
+/// A review log flattened into CSV columns.
+#[derive(Serialize, Deserialize)]
+struct ReviewLogRow {
+    version: u32,
+    card_id: i64,
+    timestamp: i64,
+    choice: String,
+    interval: i32,
+    last_interval: i32,
+    ease_factor: i32,
+    last_ease_factor: i32,
+    card_type: String,
+    time_taken: i32,
+    review_kind: String,
+}
+
+impl ReviewLogRow {
+    fn new(log: &ReviewLog) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            card_id: log.card_id,
+            timestamp: log.timestamp,
+            choice: choice_name(log.choice).to_string(),
+            interval: log.interval,
+            last_interval: log.last_interval,
+            ease_factor: log.ease_factor,
+            last_ease_factor: log.last_ease_factor,
+            card_type: card_type_name(log.card_type).to_string(),
+            time_taken: log.time_taken,
+            review_kind: review_kind_name(log.review_kind).to_string(),
+        }
+    }
+
+    fn into_review_log(self) -> Result<ReviewLog, String> {
+        Ok(ReviewLog {
+            card_id: self.card_id,
+            timestamp: self.timestamp,
+            choice: parse_choice(&self.choice)?,
+            interval: self.interval,
+            last_interval: self.last_interval,
+            ease_factor: self.ease_factor,
+            last_ease_factor: self.last_ease_factor,
+            card_type: parse_card_type(&self.card_type)?,
+            time_taken: self.time_taken,
+            review_kind: parse_review_kind(&self.review_kind)?,
+        })
+    }
+}
+
+fn write_csv<W: Write, T: Serialize>(
+    writer: W,
+    rows: impl Iterator<Item = T>,
+) -> Result<(), Error> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for row in rows {
+        writer.serialize(row).map_err(write_error)?;
+    }
+    writer.flush().map_err(write_error)
+}
+
+fn read_csv<R: Read, T, U>(
+    reader: R,
+    version: fn(&T) -> u32,
+    convert: fn(T) -> Result<U, String>,
+) -> Result<Vec<U>, Error>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let mut reader = csv::Reader::from_reader(reader);
+    let mut items = vec![];
+    for (i, row) in reader.deserialize::<T>().enumerate() {
+        // the header is line 1
+        let line = i + 2;
+        let row = row.map_err(|e| read_error(line, e))?;
+        check_version(line, version(&row))?;
+        items.push(convert(row).map_err(|e| read_error(line, e))?);
+    }
+    Ok(items)
+}
+
+pub fn write_cards_csv<W: Write>(writer: W, cards: &[Card]) -> Result<(), Error> {
+    write_csv(writer, cards.iter().map(CardRow::new))
+}
+
+/// Read cards from CSV, checking every card is consistent.
+pub fn read_cards_csv<R: Read>(reader: R) -> Result<Vec<Card>, Error> {
+    read_csv(reader, |row: &CardRow| row.version, CardRow::into_card)
+}
+
+pub fn write_review_logs_csv<W: Write>(writer: W, review_logs: &[ReviewLog]) -> Result<(), Error> {
+    write_csv(writer, review_logs.iter().map(ReviewLogRow::new))
+}
+
+pub fn read_review_logs_csv<R: Read>(reader: R) -> Result<Vec<ReviewLog>, Error> {
+    read_csv(
+        reader,
+        |row: &ReviewLogRow| row.version,
+        ReviewLogRow::into_review_log,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn cards() -> Vec<Card> {
+        vec![
+            Card {
+                id: 1,
+                due: 4,
+                ..Card::default()
+            },
+            Card {
+                id: 2,
+                note_id: 9,
+                deck_id: 3,
+                card_type: CardType::Relearn,
+                card_queue: CardQueue::Learn,
+                due: 1_614_600_600,
+                interval: 3,
+                ease_factor: 2_300,
+                reps: 8,
+                lapses: 2,
+                learning_state: Some(LearningState {
+                    kind: StepKind::Relearn,
+                    remaining: 2,
+                    remaining_today: 1,
+                }),
+                memory_state: Some(MemoryState {
+                    stability: 3.5,
+                    difficulty: 6.25,
+                }),
+                ..Card::default()
+            },
+            Card {
+                id: 3,
+                note_id: 9,
+                deck_id: 4,
+                original_deck_id: Some(3),
+                original_due: Some(18_700),
+                card_type: CardType::Review,
+                card_queue: CardQueue::Buried,
+                original_queue: Some(CardQueue::Review),
+                due: 18_690,
+                interval: 20,
+                ease_factor: 2_500,
+                reps: 5,
+                scheduler_state: Some(json!({"box": 3})),
+                ..Card::default()
+            },
+        ]
+    }
+
+    fn review_logs() -> Vec<ReviewLog> {
+        vec![ReviewLog {
+            card_id: 3,
+            timestamp: 1_614_600_000,
+            choice: Choice::Hard,
+            interval: 20,
+            last_interval: 15,
+            ease_factor: 2_350,
+            last_ease_factor: 2_500,
+            card_type: CardType::Review,
+            time_taken: 6_000,
+            review_kind: ReviewKind::Filtered,
+        }]
+    }
+
+    fn as_json(cards: &[Card]) -> Vec<Value> {
+        cards
+            .iter()
+            .map(|card| serde_json::to_value(card).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_jsonl_round_trip() {
+        let mut out = vec![];
+        write_jsonl(&mut out, &cards(), &review_logs()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("{\"format\":\"sm2\",\"version\":1}\n"));
+        assert_eq!(text.lines().count(), 5);
+
+        let records = read_jsonl(text.as_bytes()).unwrap();
+        assert_eq!(as_json(&records.cards), as_json(&cards()));
+        assert_eq!(records.review_logs, review_logs());
+    }
+
+    #[test]
+    fn test_jsonl_errors() {
+        let read = |text: &str| match read_jsonl(text.as_bytes()) {
+            Err(Error::Import(message)) => message,
+            _ => panic!("expected an error for {}", text),
+        };
+        assert!(read("").contains("empty"));
+        assert!(read("{\"format\":\"sm2\",\"version\":2}").contains("version 2"));
+        assert!(read("{\"id\":1}").starts_with("line 1"));
+
+        let header = "{\"format\":\"sm2\",\"version\":1}\n";
+        let card = "{\"record\":\"card\",\"card_type\":0,\"card_queue\":7,\"due\":0,\
+                    \"interval\":0,\"ease_factor\":0,\"reps\":0,\"lapses\":0}";
+        assert!(read(&format!("{}\n{}", header, card)).starts_with("line 3"));
+        let card = card.replace("\"card_queue\":7", "\"card_queue\":2");
+        assert!(read(&format!("{}{}", header, card)).contains("New card can not be in Review"));
+    }
+
+    #[test]
+    fn test_write_error() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::WriteZero.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        assert!(matches!(
+            write_jsonl(Full, &cards(), &[]),
+            Err(Error::Export(_))
+        ));
+        assert!(matches!(
+            write_cards_csv(Full, &cards()),
+            Err(Error::Export(_))
+        ));
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let mut out = vec![];
+        write_cards_csv(&mut out, &cards()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let mut lines = text.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("version,id,note_id,deck_id,card_type"));
+        assert!(lines.nth(1).unwrap().contains(",relearn,learn,"));
+        assert_eq!(
+            as_json(&read_cards_csv(text.as_bytes()).unwrap()),
+            as_json(&cards())
+        );
+
+        let mut out = vec![];
+        write_review_logs_csv(&mut out, &review_logs()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains(",hard,"));
+        assert_eq!(
+            read_review_logs_csv(text.as_bytes()).unwrap(),
+            review_logs()
+        );
+    }
+
+    #[test]
+    fn test_csv_columns() {
+        // optional columns can be left out, and enums given as numbers
+        let text = "version,id,card_type,card_queue,due,interval,ease_factor,reps,lapses\n\
+                    1,5,2,day_learn,18690,1,2500,3,0\n\
+                    1,6,Review,Review,18690,1,2500,3,0\n";
+        let read = |text: &str| match read_cards_csv(text.as_bytes()) {
+            Err(Error::Import(message)) => message,
+            other => panic!("expected an error, got {} cards", other.unwrap().len()),
+        };
+        assert!(read(text).starts_with("line 2: Review card can not be in DayLearn"));
+
+        let text = text.replacen("1,5,2,day_learn", "1,5,learn,day_learn", 1);
+        let cards = read_cards_csv(text.as_bytes()).unwrap();
+        assert_eq!(cards[0].card_type, CardType::Learn);
+        assert_eq!(cards[0].deck_id, DEFAULT_DECK_ID);
+        assert_eq!(cards[1].card_queue, CardQueue::Review);
+
+        let bad_queue = text.replacen("Review,Review", "review,later", 1);
+        assert_eq!(
+            read(&bad_queue),
+            "line 3: card_queue \"later\" is not one of new, learn, review, day_learn, \
+             preview, suspended, buried"
+        );
+        assert!(read(&text.replacen("\n1,6", "\n2,6", 1)).starts_with("line 3: version 2"));
+
+        // relearning cards are in relearning steps unless it says otherwise
+        let text = "version,id,card_type,card_queue,due,interval,ease_factor,reps,lapses,\
+                    learning_remaining\n\
+                    1,7,relearn,day_learn,18690,1,2300,6,1,2\n";
+        let state = read_cards_csv(text.as_bytes()).unwrap()[0]
+            .learning_state
+            .unwrap();
+        assert_eq!(state.kind, StepKind::Relearn);
+        assert_eq!(state.remaining_today, 2);
+
+        // and read the same as from JSON Lines when it says otherwise
+        let text = text
+            .replace("lapses,", "lapses,learning_kind,")
+            .replace(",1,2\n", ",1,learn,2\n");
+        let state = read_cards_csv(text.as_bytes()).unwrap()[0]
+            .learning_state
+            .unwrap();
+        assert_eq!(state.kind, StepKind::Relearn);
+    }
+}
@@ -1,10 +1,23 @@
 extern crate wasm_bindgen;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use gloo_utils::format::JsValueSerdeExt;
+use serde::Serialize;
 use wasm_bindgen::prelude::*;
 
+use crate::error::{ConfigError, Error};
 use crate::srs::card::Card;
+use crate::srs::collection::Collection;
 use crate::srs::config::Config;
-use crate::srs::scheduler::{Choice, Sched, Scheduler};
-use crate::svc::timestamp::Timestamp;
+use crate::srs::optimizer;
+use crate::srs::registry::{Registry, SchedulerFactory};
+use crate::srs::review_log::ReviewLog;
+use crate::srs::scheduler::{Choice, Sched};
+use crate::srs::simulator::{self, SimulationInput};
+use crate::srs::supermemo::{Grade, SuperMemo2};
+use crate::srs::undo::{UndoStack, UndoToken};
+use crate::svc::clock::{Clock, FixedClock, SystemClock};
 
 // Import 'window.alert'
 #[wasm_bindgen]
@@ -18,88 +31,414 @@ pub fn hello(name: &str) {
     alert(&format!("Hello, {}!", name));
 }
 
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+}
+
+/// Make a scheduling algorithm available to `Sm2` as `config.algorithm`.
+pub fn register_algorithm(name: &str, factory: SchedulerFactory) {
+    REGISTRY.with(|registry| registry.borrow_mut().register(name, factory));
+}
+
+/// Names of the algorithms `config.algorithm` can be set to.
+#[wasm_bindgen]
+pub fn algorithms() -> Vec<JsValue> {
+    REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .names()
+            .into_iter()
+            .map(JsValue::from_str)
+            .collect()
+    })
+}
+
+/// Fit the config to a list of review logs, returning
+/// `{ config, before, after }` with the log loss and RMSE of both configs.
+#[wasm_bindgen]
+pub fn optimize(config: &JsValue, review_logs: &JsValue) -> Result<JsValue, JsValue> {
+    let config = parse_config(config)?;
+    let logs: Vec<ReviewLog> = review_logs
+        .into_serde()
+        .map_err(|e| Error::InvalidReviewLog(e.to_string()))?;
+    let optimized = optimizer::optimize(&config, &logs)?;
+    Ok(result_to_js(&optimized))
+}
+
+// for results made of plain numbers and configs, which always serialize
+fn result_to_js<T: Serialize>(result: &T) -> JsValue {
+    JsValue::from_serde(result).expect("results always serialize")
+}
+
+fn build_scheduler(config: Config, clock: Box<dyn Clock>) -> Result<Box<dyn Sched>, Error> {
+    REGISTRY.with(|registry| registry.borrow().build(config, clock))
+}
+
+// number of answers that can be undone with `undo_last`
+const UNDO_LIMIT: usize = 20;
+
+/// The scheduler for `config.algorithm`. "supermemo2" is always the built in
+/// one, kept as itself so it can also answer textbook grades.
+enum Algorithm {
+    Registered(Box<dyn Sched>),
+    SuperMemo2(SuperMemo2),
+}
+
 #[wasm_bindgen]
 pub struct Sm2 {
-    scheduler: Scheduler,
+    config: Config,
+    clock: Rc<dyn Clock>,
+    algorithm: Algorithm,
+    undo: UndoStack,
+}
+
+impl From<Error> for JsValue {
+    fn from(err: Error) -> Self {
+        JsValue::from_serde(&err).unwrap_or_else(|_| JsValue::from_str(&err.to_string()))
+    }
+}
+
+fn parse_config(config: &JsValue) -> Result<Config, Error> {
+    config
+        .into_serde()
+        .map_err(|e| Error::InvalidConfig(vec![ConfigError::new("config", e.to_string())]))
+}
+
+fn parse_card(card: &JsValue) -> Result<Card, Error> {
+    let card: Card = card
+        .into_serde()
+        .map_err(|e| Error::InvalidCard(e.to_string()))?;
+    card.validate()?;
+    Ok(card)
+}
+
+fn parse_collection(collection: &JsValue) -> Result<Collection, Error> {
+    let collection: Collection = collection
+        .into_serde()
+        .map_err(|e| Error::InvalidCard(e.to_string()))?;
+    for card in &collection.cards {
+        card.validate()?;
+    }
+    Ok(collection)
+}
+
+fn parse_choice(choice: &JsValue) -> Result<Choice, Error> {
+    choice
+        .into_serde()
+        .map_err(|e| Error::InvalidChoice(e.to_string()))
+}
+
+fn parse_grade(grade: &JsValue) -> Result<Grade, Error> {
+    grade
+        .into_serde()
+        .map_err(|e| Error::InvalidChoice(e.to_string()))
+}
+
+fn card_to_js(card: &Card) -> Result<JsValue, Error> {
+    JsValue::from_serde(card).map_err(|e| Error::InvalidCard(e.to_string()))
+}
+
+fn collection_to_js(collection: &Collection) -> Result<JsValue, Error> {
+    JsValue::from_serde(collection).map_err(|e| Error::InvalidCard(e.to_string()))
+}
+
+#[derive(Serialize)]
+struct AnsweredCard<'a> {
+    card: &'a Card,
+    review_log: &'a ReviewLog,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    undo_token: Option<&'a UndoToken>,
+}
+
+#[derive(Serialize)]
+struct AnsweredCollectionCard<'a> {
+    collection: &'a Collection,
+    review_log: &'a ReviewLog,
 }
 
 #[wasm_bindgen]
 impl Sm2 {
     #[wasm_bindgen(constructor)]
-    pub fn new(config: &JsValue) -> Self {
-        let config: Config = config.into_serde().unwrap();
-        Self {
-            scheduler: Scheduler::new(config, Timestamp::day_cut_off()),
+    pub fn new(config: &JsValue) -> Result<Sm2, JsValue> {
+        Self::with_clock(parse_config(config)?, Rc::new(SystemClock))
+    }
+
+    /// Schedule as of a fixed point in time instead of the host clock,
+    /// eg when replaying reviews that were made offline.
+    pub fn with_time(config: &JsValue, now: i64, minutes_west: i32) -> Result<Sm2, JsValue> {
+        let clock = FixedClock::try_new(now, minutes_west)?;
+        Self::with_clock(parse_config(config)?, Rc::new(clock))
+    }
+}
+
+impl Sm2 {
+    fn with_clock(config: Config, clock: Rc<dyn Clock>) -> Result<Sm2, JsValue> {
+        let algorithm = if config.algorithm == "supermemo2" {
+            Algorithm::SuperMemo2(SuperMemo2::new(config.clone(), Box::new(clock.clone()))?)
+        } else {
+            Algorithm::Registered(build_scheduler(config.clone(), Box::new(clock.clone()))?)
+        };
+        Ok(Self {
+            algorithm,
+            config,
+            clock,
+            undo: UndoStack::new(UNDO_LIMIT),
+        })
+    }
+
+    fn scheduler(&self) -> &dyn Sched {
+        match &self.algorithm {
+            Algorithm::Registered(scheduler) => scheduler.as_ref(),
+            Algorithm::SuperMemo2(supermemo) => supermemo,
         }
     }
 }
 
 #[wasm_bindgen]
 impl Sm2 {
-    pub fn next_interval(&self, card: &JsValue, choice: &JsValue) -> i64 {
-        let card: Card = card.into_serde().unwrap();
-        let choice: Choice = choice.into_serde().unwrap();
-        self.scheduler.next_interval(&card, choice)
+    pub fn next_interval(&self, card: &JsValue, choice: &JsValue) -> Result<i64, JsValue> {
+        let card = parse_card(card)?;
+        let choice = parse_choice(choice)?;
+        Ok(self.scheduler().next_interval(&card, choice))
     }
 
-    pub fn next_interval_string(&self, card: &JsValue, choice: &JsValue) -> String {
-        let card: Card = card.into_serde().unwrap();
-        let choice: Choice = choice.into_serde().unwrap();
-        self.scheduler.next_interval_string(&card, choice)
+    pub fn next_interval_string(
+        &self,
+        card: &JsValue,
+        choice: &JsValue,
+    ) -> Result<String, JsValue> {
+        let card = parse_card(card)?;
+        let choice = parse_choice(choice)?;
+        Ok(self.scheduler().next_interval_string(&card, choice))
     }
 
-    pub fn answer_card(&self, card: &JsValue, choice: &JsValue) -> JsValue {
-        let mut card: Card = card.into_serde().unwrap();
-        let choice: Choice = choice.into_serde().unwrap();
-        self.scheduler.answer_card(&mut card, choice);
-        JsValue::from_serde(&card).unwrap()
+    pub fn answer_card(&self, card: &JsValue, choice: &JsValue) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        let choice = parse_choice(choice)?;
+        self.scheduler().answer_card(&mut card, choice, 0);
+        Ok(card_to_js(&card)?)
     }
 
-    pub fn bury_card(&self, card: &JsValue) -> JsValue {
-        let mut card: Card = card.into_serde().unwrap();
-        self.scheduler.bury_card(&mut card);
-        JsValue::from_serde(&card).unwrap()
+    /// Answer with a textbook SM-2 quality from 0 to 5. Four button
+    /// algorithms treat 0-2 as Again, 3 as Hard, 4 as Ok and 5 as Easy.
+    pub fn answer_card_grade(&self, card: &JsValue, grade: &JsValue) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        let grade = parse_grade(grade)?;
+        match &self.algorithm {
+            Algorithm::SuperMemo2(supermemo) => supermemo.answer_card_grade(&mut card, grade, 0),
+            Algorithm::Registered(scheduler) => scheduler.answer_card(&mut card, grade.into(), 0),
+        };
+        Ok(card_to_js(&card)?)
     }
 
-    pub fn unbury_card(&self, card: &JsValue) -> JsValue {
-        let mut card: Card = card.into_serde().unwrap();
-        self.scheduler.unbury_card(&mut card);
-        JsValue::from_serde(&card).unwrap()
+    /// Answer the card and return `{ card, review_log }`. `time_taken` is the
+    /// milliseconds the user spent on the card.
+    pub fn answer_card_with_log(
+        &self,
+        card: &JsValue,
+        choice: &JsValue,
+        time_taken: i32,
+    ) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        let choice = parse_choice(choice)?;
+        let review_log = self.scheduler().answer_card(&mut card, choice, time_taken);
+        let answered = AnsweredCard {
+            card: &card,
+            review_log: &review_log,
+            undo_token: None,
+        };
+        Ok(JsValue::from_serde(&answered).map_err(|e| Error::InvalidCard(e.to_string()))?)
     }
 
-    pub fn suspend_card(&self, card: &JsValue) -> JsValue {
-        let mut card: Card = card.into_serde().unwrap();
-        self.scheduler.suspend_card(&mut card);
-        JsValue::from_serde(&card).unwrap()
+    /// Answer the card and return `{ card, review_log, undo_token }`. The
+    /// previous card is also kept so the last answers can be undone with
+    /// `undo_last`.
+    pub fn answer_card_with_undo(
+        &mut self,
+        card: &JsValue,
+        choice: &JsValue,
+        time_taken: i32,
+    ) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        let choice = parse_choice(choice)?;
+        let (review_log, undo_token) = self
+            .scheduler()
+            .answer_card_with_undo(&mut card, choice, time_taken);
+        let answered = AnsweredCard {
+            card: &card,
+            review_log: &review_log,
+            undo_token: Some(&undo_token),
+        };
+        let value =
+            JsValue::from_serde(&answered).map_err(|e| Error::InvalidCard(e.to_string()))?;
+        self.undo.push(undo_token);
+        Ok(value)
     }
 
-    pub fn unsuspend_card(&self, card: &JsValue) -> JsValue {
-        let mut card: Card = card.into_serde().unwrap();
-        self.scheduler.unsuspend_card(&mut card);
-        JsValue::from_serde(&card).unwrap()
+    /// Return the card as it was before the answer that produced `token`,
+    /// throwing an `inconsistent_card` error if the token's card is.
+    pub fn undo_card(&self, token: &JsValue) -> Result<JsValue, JsValue> {
+        let token: UndoToken = token
+            .into_serde()
+            .map_err(|e| Error::InvalidInput(format!("not an undo token: {}", e)))?;
+        // tokens come back from JavaScript, and may have been edited
+        let card = token.restore();
+        card.validate()?;
+        Ok(card_to_js(&card)?)
     }
 
-    pub fn schedule_card_as_new(&self, card: &JsValue) -> JsValue {
-        let mut card: Card = card.into_serde().unwrap();
-        self.scheduler.schedule_card_as_new(&mut card);
-        JsValue::from_serde(&card).unwrap()
+    /// Return the card as it was before the most recent answer made with
+    /// `answer_card_with_undo`, or `null` if there is nothing to undo.
+    pub fn undo_last(&mut self) -> Result<JsValue, JsValue> {
+        match self.undo.pop() {
+            Some(token) => Ok(card_to_js(&token.restore())?),
+            None => Ok(JsValue::NULL),
+        }
     }
 
-    pub fn schedule_card_as_review(&self, card: &JsValue, min_days: i32, max_days: i32) -> JsValue {
-        let mut card: Card = card.into_serde().unwrap();
-        self.scheduler
+    pub fn bury_card(&self, card: &JsValue) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        self.scheduler().bury_card(&mut card);
+        Ok(card_to_js(&card)?)
+    }
+
+    pub fn unbury_card(&self, card: &JsValue) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        self.scheduler().unbury_card(&mut card);
+        Ok(card_to_js(&card)?)
+    }
+
+    pub fn suspend_card(&self, card: &JsValue) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        self.scheduler().suspend_card(&mut card);
+        Ok(card_to_js(&card)?)
+    }
+
+    pub fn unsuspend_card(&self, card: &JsValue) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        self.scheduler().unsuspend_card(&mut card);
+        Ok(card_to_js(&card)?)
+    }
+
+    pub fn schedule_card_as_new(&self, card: &JsValue) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        self.scheduler().schedule_card_as_new(&mut card);
+        Ok(card_to_js(&card)?)
+    }
+
+    pub fn schedule_card_as_review(
+        &self,
+        card: &JsValue,
+        min_days: i32,
+        max_days: i32,
+    ) -> Result<JsValue, JsValue> {
+        let mut card = parse_card(card)?;
+        self.scheduler()
             .schedule_card_as_review(&mut card, min_days, max_days);
-        JsValue::from_serde(&card).unwrap()
+        Ok(card_to_js(&card)?)
     }
 
     pub fn day_cut_off(&self) -> i64 {
-        self.scheduler.day_cut_off
+        self.scheduler().day_cut_off()
     }
 
     pub fn day_today(&self) -> i64 {
-        self.scheduler.day_today
+        self.scheduler().day_today()
+    }
+
+    /// Ids of the deck's cards that can be studied now, as
+    /// `{ learn, review, day_learn, new, learn_ahead }`.
+    pub fn build_queues(&self, collection: &JsValue, deck_id: i64) -> Result<JsValue, JsValue> {
+        let mut collection = parse_collection(collection)?;
+        let queues = collection.build_queues(deck_id, self.scheduler(), &self.config);
+        Ok(JsValue::from_serde(&queues).map_err(|e| Error::InvalidCard(e.to_string()))?)
+    }
+
+    /// The deck's card to show next, or `undefined` when it's done for today.
+    pub fn get_next_card(&self, collection: &JsValue, deck_id: i64) -> Result<JsValue, JsValue> {
+        let mut collection = parse_collection(collection)?;
+        match collection.get_next_card(deck_id, self.scheduler(), &self.config) {
+            Some(card) => Ok(card_to_js(card)?),
+            None => Ok(JsValue::UNDEFINED),
+        }
+    }
+
+    /// Answer a card of the collection, returning `{ collection, review_log }`
+    /// with the card and the deck's daily counts updated.
+    pub fn answer_collection_card(
+        &self,
+        collection: &JsValue,
+        card_id: i64,
+        choice: &JsValue,
+        time_taken: i32,
+    ) -> Result<JsValue, JsValue> {
+        let mut collection = parse_collection(collection)?;
+        let choice = parse_choice(choice)?;
+        let review_log =
+            collection.answer_card(card_id, choice, time_taken, self.scheduler(), &self.config)?;
+        let answered = AnsweredCollectionCard {
+            collection: &collection,
+            review_log: &review_log,
+        };
+        Ok(JsValue::from_serde(&answered).map_err(|e| Error::InvalidCard(e.to_string()))?)
+    }
+
+    /// Unbury the collection's buried cards if the day has rolled over since
+    /// it was last done, returning the collection.
+    pub fn unbury_all_for_new_day(&self, collection: &JsValue) -> Result<JsValue, JsValue> {
+        let mut collection = parse_collection(collection)?;
+        collection.unbury_all_for_new_day(self.scheduler());
+        Ok(collection_to_js(&collection)?)
+    }
+
+    /// Fill a filtered deck with the cards matching its `filter`, returning
+    /// the collection.
+    pub fn rebuild_filtered_deck(
+        &self,
+        collection: &JsValue,
+        deck_id: i64,
+    ) -> Result<JsValue, JsValue> {
+        let mut collection = parse_collection(collection)?;
+        collection.rebuild_filtered_deck(deck_id, self.scheduler())?;
+        Ok(collection_to_js(&collection)?)
+    }
+
+    /// Send the cards of a filtered deck back to their decks, returning the
+    /// collection.
+    pub fn empty_filtered_deck(
+        &self,
+        collection: &JsValue,
+        deck_id: i64,
+    ) -> Result<JsValue, JsValue> {
+        let mut collection = parse_collection(collection)?;
+        collection.empty_filtered_deck(deck_id)?;
+        Ok(collection_to_js(&collection)?)
+    }
+
+    /// Study a deck of `{ deck_size, new_per_day, days, recall }` new cards
+    /// from today with this config, returning the daily workload.
+    pub fn simulate(&self, input: &JsValue) -> Result<JsValue, JsValue> {
+        let input: SimulationInput = input
+            .into_serde()
+            .map_err(|e| Error::InvalidInput(format!("simulation input: {}", e)))?;
+        let simulation = REGISTRY.with(|registry| {
+            simulator::simulate(
+                &registry.borrow(),
+                &self.config,
+                &input,
+                self.clock.as_ref(),
+            )
+        })?;
+        Ok(result_to_js(&simulation))
     }
 }
 
+#[cfg(all(feature = "anki", not(target_arch = "wasm32")))]
+pub mod anki;
+pub mod error;
+#[cfg(feature = "interchange")]
+pub mod interchange;
 pub mod srs;
+#[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+pub mod storage;
 pub mod svc;
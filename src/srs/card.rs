@@ -1,7 +1,13 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(PartialEq, Clone, Copy, Serialize_repr, Deserialize_repr)]
+use crate::error::Error;
+
+// deck cards are in when nothing says otherwise
+pub const DEFAULT_DECK_ID: i64 = 1;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize_repr, Deserialize_repr)]
 #[repr(i8)]
 pub enum CardType {
     New = 0,
@@ -10,7 +16,7 @@ pub enum CardType {
     Relearn = 3,
 }
 
-#[derive(PartialEq, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize_repr, Deserialize_repr)]
 #[repr(i8)]
 pub enum CardQueue {
     // due is the order cards are shown in
@@ -23,13 +29,111 @@ pub enum CardQueue {
     Review = 2,
     DayLearn = 3,
 
+    // previewed in a filtered deck, due is number of seconds since epoch
+    Preview = 4,
+
     /// cards are not due in these states
     Suspended = -1,
     Buried = -2,
 }
 
+/// FSRS memory of a card: days until recall drops to 90%, and how hard
+/// the card is from 1 to 10.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct MemoryState {
+    pub stability: f32,
+    pub difficulty: f32,
+}
+
+/// Which steps a learning card is going through.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[repr(i8)]
+pub enum StepKind {
+    Learn = 0,
+    Relearn = 1,
+}
+
+impl StepKind {
+    pub fn for_card_type(card_type: CardType) -> Self {
+        match card_type {
+            CardType::Relearn => StepKind::Relearn,
+            _ => StepKind::Learn,
+        }
+    }
+}
+
+/// Where a learning or relearning card is in its steps.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct LearningState {
+    pub kind: StepKind,
+    // steps left before graduating, counting the current one
+    pub remaining: u32,
+    // how many of those can be done before the day cut off
+    pub remaining_today: u32,
+}
+
+impl LearningState {
+    /// Index of the current step, given how many steps there are.
+    pub fn step(&self, total: usize) -> usize {
+        total.saturating_sub(self.remaining as usize)
+    }
+
+    /// Read the old `remaining_steps`, which packed both counts into
+    /// `remaining_today * 1000 + remaining`. It didn't record the kind of
+    /// steps, so that comes from the card type.
+    pub fn from_packed(packed: i32, card_type: CardType) -> Option<Self> {
+        if packed <= 0 {
+            return None;
+        }
+        Some(Self {
+            kind: StepKind::for_card_type(card_type),
+            remaining: (packed % 1_000) as u32,
+            remaining_today: (packed / 1_000) as u32,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredLearningState {
+    Packed(i32),
+    State(LearningState),
+}
+
+fn deserialize_learning_state<'de, D>(deserializer: D) -> Result<Option<LearningState>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        match Option::<StoredLearningState>::deserialize(deserializer)? {
+            None => None,
+            // the kind is fixed up from the card type once the card is read
+            Some(StoredLearningState::Packed(packed)) => {
+                LearningState::from_packed(packed, CardType::Learn)
+            }
+            Some(StoredLearningState::State(state)) => Some(state),
+        },
+    )
+}
+
+// derived as inherent functions, so the trait impls below can finish off
+// the card once all its fields are read
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub struct Card {
+    // seeds the interval fuzz, so it should be unique per card
+    #[serde(default)]
+    pub id: i64,
+    // cards made from the same note are siblings, 0 is no note
+    #[serde(default)]
+    pub note_id: i64,
+    #[serde(default = "default_deck_id")]
+    pub deck_id: i64,
+    // home deck and due of a card moved to a filtered deck
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_deck_id: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_due: Option<i64>,
     pub card_type: CardType,
     pub card_queue: CardQueue,
     pub due: i64,
@@ -37,12 +141,51 @@ pub struct Card {
     pub ease_factor: i32,
     pub reps: i32,
     pub lapses: i32,
-    pub remaining_steps: i32,
+    // set while the card is in learning or relearning; cards saved before
+    // this existed have a packed `remaining_steps` instead
+    #[serde(
+        default,
+        alias = "remaining_steps",
+        deserialize_with = "deserialize_learning_state",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub learning_state: Option<LearningState>,
+    // where a buried card goes back to when it's unburied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_queue: Option<CardQueue>,
+    // day a buried card was buried on, so it stays buried until the next
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buried_day: Option<i64>,
+    // only set by the FSRS scheduler
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_state: Option<MemoryState>,
+    // free-form state for schedulers that need more than the fields above
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheduler_state: Option<Value>,
+}
+
+impl Serialize for Card {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Card::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Card {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut card = Card::deserialize(deserializer)?;
+        card.fix_step_kind();
+        Ok(card)
+    }
 }
 
 impl Default for Card {
     fn default() -> Self {
         Self {
+            id: 0,
+            note_id: 0,
+            deck_id: DEFAULT_DECK_ID,
+            original_deck_id: None,
+            original_due: None,
             card_type: CardType::New,
             card_queue: CardQueue::New,
             due: 0,
@@ -50,11 +193,19 @@ impl Default for Card {
             ease_factor: 0,
             reps: 0,
             lapses: 0,
-            remaining_steps: 0,
+            learning_state: None,
+            original_queue: None,
+            buried_day: None,
+            memory_state: None,
+            scheduler_state: None,
         }
     }
 }
 
+fn default_deck_id() -> i64 {
+    DEFAULT_DECK_ID
+}
+
 impl Card {
     pub fn new(due: i64) -> Self {
         Card {
@@ -63,6 +214,16 @@ impl Card {
         }
     }
 
+    /// Relearning cards are always in relearning steps, and packed states
+    /// and hand-made files don't always say so.
+    pub(crate) fn fix_step_kind(&mut self) {
+        if let Some(state) = self.learning_state.as_mut() {
+            if self.card_type == CardType::Relearn {
+                state.kind = StepKind::Relearn;
+            }
+        }
+    }
+
     pub fn schedule_as_new(&mut self, position: i64, initial_ease: i32) {
         self.due = position;
         self.card_type = CardType::New;
@@ -83,12 +244,62 @@ impl Card {
         }
     }
 
+    /// The queue a card of this type belongs in when it isn't suspended or
+    /// buried.
+    pub fn queue_for_type(&self) -> CardQueue {
+        match self.card_type {
+            CardType::Learn | CardType::Relearn => {
+                if self.due > 1_000_000_000 {
+                    CardQueue::Learn
+                } else {
+                    CardQueue::DayLearn
+                }
+            }
+            CardType::New => CardQueue::New,
+            CardType::Review => CardQueue::Review,
+        }
+    }
+
     pub fn set_new_position(&mut self, position: i64) {
         if self.card_queue != CardQueue::New || self.card_type != CardType::New {
             return;
         }
         self.due = position;
     }
+
+    /// Check that the queue agrees with the type and that counters are sane.
+    pub fn validate(&self) -> Result<(), Error> {
+        let queue_ok = match self.card_queue {
+            CardQueue::New => self.card_type == CardType::New,
+            CardQueue::Learn | CardQueue::DayLearn => {
+                matches!(self.card_type, CardType::Learn | CardType::Relearn)
+            }
+            CardQueue::Review => self.card_type == CardType::Review,
+            CardQueue::Suspended | CardQueue::Buried | CardQueue::Preview => true,
+        };
+        if !queue_ok {
+            return Err(Error::InconsistentCard(format!(
+                "{:?} card can not be in {:?} queue",
+                self.card_type, self.card_queue
+            )));
+        }
+
+        for (name, value) in &[
+            ("interval", self.interval),
+            ("ease_factor", self.ease_factor),
+            ("reps", self.reps),
+            ("lapses", self.lapses),
+        ] {
+            if *value < 0 {
+                return Err(Error::InconsistentCard(format!(
+                    "{} must not be negative",
+                    name
+                )));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -107,4 +318,72 @@ mod tests {
         card.set_new_position(2);
         assert_eq!(card.due, 1);
     }
+
+    #[test]
+    fn test_validate() {
+        let mut card = Card::default();
+        assert_eq!(card.validate(), Ok(()));
+
+        card.card_queue = CardQueue::Review;
+        assert_eq!(
+            card.validate(),
+            Err(Error::InconsistentCard(
+                "New card can not be in Review queue".to_string()
+            ))
+        );
+
+        card.card_queue = CardQueue::Suspended;
+        assert_eq!(card.validate(), Ok(()));
+
+        card.lapses = -1;
+        assert_eq!(
+            card.validate(),
+            Err(Error::InconsistentCard(
+                "lapses must not be negative".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_learning_state() {
+        let json = r#"{"card_type":1,"card_queue":1,"due":0,"interval":0,
+            "ease_factor":0,"reps":1,"lapses":0,"remaining_steps":2003}"#;
+        let card: Card = serde_json::from_str(json).unwrap();
+        let state = card.learning_state.unwrap();
+        assert_eq!(
+            state,
+            LearningState {
+                kind: StepKind::Learn,
+                remaining: 3,
+                remaining_today: 2,
+            }
+        );
+        assert_eq!(state.step(4), 1);
+
+        let json = serde_json::to_value(&card).unwrap();
+        assert!(json.get("remaining_steps").is_none());
+        assert_eq!(
+            json["learning_state"],
+            serde_json::json!({"kind": 0, "remaining": 3, "remaining_today": 2})
+        );
+        let card: Card = serde_json::from_value(json).unwrap();
+        assert_eq!(card.learning_state, Some(state));
+
+        let json = r#"{"card_type":3,"card_queue":1,"due":0,"interval":1,
+            "ease_factor":2300,"reps":5,"lapses":1,"remaining_steps":1001}"#;
+        let card: Card = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            card.learning_state,
+            Some(LearningState {
+                kind: StepKind::Relearn,
+                remaining: 1,
+                remaining_today: 1,
+            })
+        );
+
+        let json = r#"{"card_type":0,"card_queue":0,"due":0,"interval":0,
+            "ease_factor":0,"reps":0,"lapses":0,"remaining_steps":0}"#;
+        let card: Card = serde_json::from_str(json).unwrap();
+        assert_eq!(card.learning_state, None);
+    }
 }
@@ -0,0 +1,770 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue};
+use crate::srs::config::Config;
+use crate::srs::filtered::{self, Filter};
+use crate::srs::review_log::ReviewLog;
+use crate::srs::scheduler::{Choice, Sched};
+
+pub use crate::srs::card::DEFAULT_DECK_ID;
+
+// learning cards due this soon are shown early once nothing else is left
+pub const LEARN_AHEAD_SECS: i64 = 20 * 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Deck {
+    pub id: i64,
+    // "::" separates subdecks, eg "Japanese::Vocab"
+    pub name: String,
+    // cards answered in the deck and its subdecks, for the daily limits
+    #[serde(default)]
+    pub studied: DayCounts,
+    // set for filtered decks, which borrow cards from other decks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    // id of the deck's options in the collection's `configs`; decks without
+    // one use the config the queues are built with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_id: Option<i64>,
+}
+
+/// New and review cards answered on `day`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DayCounts {
+    pub day: i64,
+    pub new: i32,
+    pub review: i32,
+}
+
+impl Deck {
+    pub(crate) fn new(id: i64, name: &str) -> Self {
+        Self {
+            id,
+            name: name.to_string(),
+            studied: DayCounts::default(),
+            filter: None,
+            config_id: None,
+        }
+    }
+
+    /// Cards answered today; counts from an earlier day are stale.
+    pub fn studied_today(&self, today: i64) -> DayCounts {
+        if self.studied.day == today {
+            self.studied
+        } else {
+            DayCounts {
+                day: today,
+                ..DayCounts::default()
+            }
+        }
+    }
+
+    /// Whether this is `other` or one of its subdecks.
+    fn is_in(&self, other: &Deck) -> bool {
+        self.name == other.name || self.name.starts_with(&format!("{}::", other.name))
+    }
+}
+
+/// New and review cards a deck can still show today.
+struct Left {
+    new: i32,
+    review: i32,
+    new_ignore_review_limit: bool,
+}
+
+#[derive(Default)]
+struct Limits {
+    left: HashMap<i64, Left>,
+    // each deck and the decks above it that are being studied
+    parents: HashMap<i64, Vec<i64>>,
+}
+
+impl Limits {
+    /// Count a card of `deck_id` against its deck and the decks above it,
+    /// if none of them are at their limit.
+    fn take(&mut self, deck_id: i64, new: bool) -> bool {
+        let parents = match self.parents.get(&deck_id) {
+            Some(parents) => parents,
+            None => return false,
+        };
+        let fits = parents.iter().all(|id| {
+            let left = &self.left[id];
+            if new {
+                left.new > 0 && (left.new_ignore_review_limit || left.review > 0)
+            } else {
+                left.review > 0
+            }
+        });
+        if fits {
+            for id in parents {
+                let left = self.left.get_mut(id).unwrap();
+                if new {
+                    left.new -= 1;
+                }
+                if !new || !left.new_ignore_review_limit {
+                    left.review -= 1;
+                }
+            }
+        }
+        fits
+    }
+}
+
+/// Decks and the cards in them.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Collection {
+    pub decks: Vec<Deck>,
+    pub cards: Vec<Card>,
+    // day the buried cards were last unburied, for cards buried without a
+    // `buried_day`
+    #[serde(default)]
+    pub unburied_day: i64,
+    // deck options by id, for decks with a `config_id`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub configs: BTreeMap<i64, Config>,
+}
+
+/// Ids of the cards to study, each queue in the order it's shown.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Queues {
+    // intraday learning due now
+    pub learn: Vec<i64>,
+    pub review: Vec<i64>,
+    // interday learning due today
+    pub day_learn: Vec<i64>,
+    pub new: Vec<i64>,
+    // intraday learning due within `LEARN_AHEAD_SECS`
+    pub learn_ahead: Vec<i64>,
+}
+
+impl Queues {
+    /// The card to show next, in Anki's order: learning due now, reviews,
+    /// interday learning, new cards, then learning due soon.
+    pub fn next(&self) -> Option<i64> {
+        self.learn
+            .first()
+            .or_else(|| self.review.first())
+            .or_else(|| self.day_learn.first())
+            .or_else(|| self.new.first())
+            .or_else(|| self.learn_ahead.first())
+            .copied()
+    }
+}
+
+impl Default for Collection {
+    fn default() -> Self {
+        Self {
+            decks: vec![Deck::new(DEFAULT_DECK_ID, "Default")],
+            cards: vec![],
+            unburied_day: 0,
+            configs: BTreeMap::new(),
+        }
+    }
+}
+
+impl Collection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_deck(&mut self, name: &str) -> i64 {
+        let id = self.next_deck_id();
+        self.decks.push(Deck::new(id, name));
+        id
+    }
+
+    pub(crate) fn next_deck_id(&self) -> i64 {
+        self.decks.iter().map(|deck| deck.id).max().unwrap_or(0) + 1
+    }
+
+    pub fn deck(&self, id: i64) -> Option<&Deck> {
+        self.decks.iter().find(|deck| deck.id == id)
+    }
+
+    /// The options a deck uses, falling back to `default` for decks without
+    /// their own.
+    pub fn deck_config<'a>(&'a self, deck_id: i64, default: &'a Config) -> &'a Config {
+        self.deck(deck_id)
+            .and_then(|deck| deck.config_id)
+            .and_then(|id| self.configs.get(&id))
+            .unwrap_or(default)
+    }
+
+    /// Add a card to a deck, giving it a new id if it has none.
+    pub fn add_card(&mut self, deck_id: i64, mut card: Card) -> Result<i64, Error> {
+        if self.deck(deck_id).is_none() {
+            return Err(Error::InvalidDeck(format!("no deck with id {}", deck_id)));
+        }
+        card.validate()?;
+        if card.id == 0 {
+            card.id = self.cards.iter().map(|card| card.id).max().unwrap_or(0) + 1;
+        } else if self.card(card.id).is_some() {
+            return Err(Error::InvalidCard(format!(
+                "card {} is already in the collection",
+                card.id
+            )));
+        }
+        card.deck_id = deck_id;
+        let id = card.id;
+        self.cards.push(card);
+        Ok(id)
+    }
+
+    pub fn card(&self, id: i64) -> Option<&Card> {
+        self.cards.iter().find(|card| card.id == id)
+    }
+
+    pub fn card_mut(&mut self, id: i64) -> Option<&mut Card> {
+        self.cards.iter_mut().find(|card| card.id == id)
+    }
+
+    /// Ids of the deck and its subdecks.
+    pub(crate) fn deck_ids(&self, deck_id: i64) -> Vec<i64> {
+        let parent = match self.deck(deck_id) {
+            Some(deck) => deck,
+            None => return vec![],
+        };
+        self.decks
+            .iter()
+            .filter(|deck| deck.is_in(parent))
+            .map(|deck| deck.id)
+            .collect()
+    }
+
+    /// Return cards buried before today to the queues they were buried
+    /// from, returning how many were unburied. Cards that don't say when
+    /// they were buried are unburied on the first call of a new day.
+    pub fn unbury_all_for_new_day(&mut self, sched: &dyn Sched) -> usize {
+        let today = sched.day_today();
+        let new_day = self.unburied_day != today;
+        self.unburied_day = today;
+
+        let mut unburied = 0;
+        for card in &mut self.cards {
+            let due = card.buried_day.map_or(new_day, |day| day < today);
+            if card.card_queue == CardQueue::Buried && due {
+                sched.unbury_card(card);
+                unburied += 1;
+            }
+        }
+        unburied
+    }
+
+    /// Sort the cards of a deck and its subdecks that can be studied now
+    /// into queues, leaving out new and review cards over the daily limits
+    /// of their deck or any deck above it, up to the one being studied. Each
+    /// deck's limits come from its own config, or `config` if it has none.
+    /// Learning cards are never limited, and neither are filtered decks,
+    /// which show all their reviews whether they're due or not, to cram or
+    /// review ahead.
+    pub fn build_queues(&mut self, deck_id: i64, sched: &dyn Sched, config: &Config) -> Queues {
+        self.unbury_all_for_new_day(sched);
+        let deck_ids = self.deck_ids(deck_id);
+        let filtered = self.deck(deck_id).is_some_and(|deck| deck.filter.is_some());
+        let today = if filtered {
+            i64::MAX
+        } else {
+            sched.day_today()
+        };
+        let now = sched.now();
+        let learn_ahead = (now + LEARN_AHEAD_SECS).min(sched.day_cut_off());
+
+        let mut learn = vec![];
+        let mut review = vec![];
+        let mut day_learn = vec![];
+        let mut new = vec![];
+        let mut ahead = vec![];
+        for card in self
+            .cards
+            .iter()
+            .filter(|card| deck_ids.contains(&card.deck_id))
+        {
+            let key = (card.due, card.id, card.deck_id);
+            match card.card_queue {
+                CardQueue::Learn | CardQueue::Preview if card.due <= now => learn.push(key),
+                CardQueue::Learn | CardQueue::Preview if card.due < learn_ahead => ahead.push(key),
+                CardQueue::Review if card.due <= today => review.push(key),
+                CardQueue::DayLearn if card.due <= today => day_learn.push(key),
+                CardQueue::New => new.push(key),
+                _ => {}
+            }
+        }
+
+        let ids = |mut cards: Vec<(i64, i64, i64)>| -> Vec<i64> {
+            cards.sort_unstable();
+            cards.into_iter().map(|(_, id, _)| id).collect()
+        };
+        if !filtered {
+            // reviews come first, so new cards get what's left of the
+            // review limits
+            let mut limits = self.limits(&deck_ids, sched.day_today(), config);
+            review.sort_unstable();
+            review.retain(|&(_, _, deck)| limits.take(deck, false));
+            new.sort_unstable();
+            new.retain(|&(_, _, deck)| limits.take(deck, true));
+        }
+        Queues {
+            learn: ids(learn),
+            review: ids(review),
+            day_learn: ids(day_learn),
+            new: ids(new),
+            learn_ahead: ids(ahead),
+        }
+    }
+
+    /// What's left of today's limits in each of the decks.
+    fn limits(&self, deck_ids: &[i64], today: i64, config: &Config) -> Limits {
+        let mut limits = Limits::default();
+        for deck in deck_ids.iter().filter_map(|id| self.deck(*id)) {
+            let config = self.deck_config(deck.id, config);
+            let studied = deck.studied_today(today);
+            let mut review_left = config.reviews_per_day - studied.review;
+            if !config.new_ignore_review_limit {
+                review_left -= studied.new;
+            }
+            let new_left = config.new_per_day - studied.new;
+            limits.left.insert(
+                deck.id,
+                Left {
+                    new: new_left.max(0),
+                    review: review_left.max(0),
+                    new_ignore_review_limit: config.new_ignore_review_limit,
+                },
+            );
+            let parents = deck_ids
+                .iter()
+                .filter_map(|id| self.deck(*id))
+                .filter(|parent| deck.is_in(parent))
+                .map(|parent| parent.id)
+                .collect();
+            limits.parents.insert(deck.id, parents);
+        }
+        limits
+    }
+
+    /// The card of the deck to show next, if any are left today.
+    pub fn get_next_card(
+        &mut self,
+        deck_id: i64,
+        sched: &dyn Sched,
+        config: &Config,
+    ) -> Option<&Card> {
+        let id = self.build_queues(deck_id, sched, config).next()?;
+        self.card(id)
+    }
+
+    /// Answer a card, counting it towards the daily limits of its deck and
+    /// the decks above it, and burying its siblings as the config says.
+    /// Cards in a filtered deck are answered as its filter says.
+    pub fn answer_card(
+        &mut self,
+        card_id: i64,
+        choice: Choice,
+        time_taken: i32,
+        sched: &dyn Sched,
+        config: &Config,
+    ) -> Result<ReviewLog, Error> {
+        self.unbury_all_for_new_day(sched);
+        let index = self
+            .cards
+            .iter()
+            .position(|card| card.id == card_id)
+            .ok_or_else(|| Error::InvalidCard(format!("no card with id {}", card_id)))?;
+        let card = &self.cards[index];
+        let queue = card.card_queue;
+        let deck_id = card.deck_id;
+        let note_id = card.note_id;
+        // siblings are buried by the options of the answered card's home deck
+        let bury_config = self
+            .deck_config(card.original_deck_id.unwrap_or(deck_id), config)
+            .clone();
+        let filter = self.deck(deck_id).and_then(|deck| deck.filter.clone());
+
+        let card = &mut self.cards[index];
+        let log = match filter {
+            Some(filter) => filtered::answer_card(card, &filter, choice, time_taken, sched),
+            None => sched.answer_card(card, choice, time_taken),
+        };
+        if note_id != 0 {
+            self.bury_siblings(card_id, note_id, sched, &bury_config);
+        }
+
+        if let Some(deck) = self.deck(deck_id).cloned() {
+            let today = sched.day_today();
+            for parent in self.decks.iter_mut().filter(|parent| deck.is_in(parent)) {
+                let mut studied = parent.studied_today(today);
+                match queue {
+                    CardQueue::New => studied.new += 1,
+                    CardQueue::Review => studied.review += 1,
+                    _ => {}
+                }
+                parent.studied = studied;
+            }
+        }
+        Ok(log)
+    }
+
+    fn bury_siblings(&mut self, card_id: i64, note_id: i64, sched: &dyn Sched, config: &Config) {
+        let today = sched.day_today();
+        for card in &mut self.cards {
+            if card.note_id != note_id || card.id == card_id {
+                continue;
+            }
+            let bury = match card.card_queue {
+                CardQueue::New => config.bury_new,
+                CardQueue::Review => config.bury_reviews && card.due <= today,
+                CardQueue::DayLearn => config.bury_interday_learning && card.due <= today,
+                _ => false,
+            };
+            if bury {
+                sched.bury_card(card);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::srs::card::{CardType, LearningState};
+    use crate::srs::config::Config;
+    use crate::srs::test_util::{review, scheduler_on, NOW};
+    use crate::svc::clock::FixedClock;
+
+    use super::*;
+
+    fn learn(queue: CardQueue, due: i64) -> Card {
+        Card {
+            card_type: CardType::Learn,
+            card_queue: queue,
+            due,
+            learning_state: LearningState::from_packed(1, CardType::Learn),
+            ..Card::default()
+        }
+    }
+
+    #[test]
+    fn test_queue_order() {
+        let clock = Rc::new(FixedClock::new(NOW, 0));
+        let sched = scheduler_on(&clock);
+        let config = Config::default();
+        let today = sched.day_today();
+
+        let mut col = Collection::new();
+        let deck = DEFAULT_DECK_ID;
+        let new_2 = col.add_card(deck, Card::new(2)).unwrap();
+        let new_1 = col.add_card(deck, Card::new(1)).unwrap();
+        let overdue = col.add_card(deck, review(today - 3)).unwrap();
+        let due = col.add_card(deck, review(today)).unwrap();
+        col.add_card(deck, review(today + 1)).unwrap();
+        let day_learn = col
+            .add_card(deck, learn(CardQueue::DayLearn, today))
+            .unwrap();
+        let learn_now = col.add_card(deck, learn(CardQueue::Learn, NOW)).unwrap();
+        let soon = col
+            .add_card(deck, learn(CardQueue::Learn, NOW + 600))
+            .unwrap();
+        col.add_card(deck, learn(CardQueue::Learn, NOW + 3_600))
+            .unwrap();
+
+        let queues = col.build_queues(deck, &sched, &config);
+        assert_eq!(
+            queues,
+            Queues {
+                learn: vec![learn_now],
+                review: vec![overdue, due],
+                day_learn: vec![day_learn],
+                new: vec![new_1, new_2],
+                learn_ahead: vec![soon],
+            }
+        );
+        assert_eq!(
+            col.get_next_card(deck, &sched, &config).unwrap().id,
+            learn_now
+        );
+
+        // Study everything; the learning card due in an hour is left
+        let mut order = vec![];
+        while let Some(id) = col.build_queues(deck, &sched, &config).next() {
+            order.push(id);
+            col.answer_card(id, Choice::Easy, 0, &sched, &config)
+                .unwrap();
+        }
+        assert_eq!(
+            order,
+            vec![learn_now, overdue, due, day_learn, new_1, new_2, soon]
+        );
+
+        // It's shown once it's due
+        clock.advance(3_600);
+        assert!(col.get_next_card(deck, &sched, &config).is_some());
+    }
+
+    #[test]
+    fn test_subdecks() {
+        let clock = Rc::new(FixedClock::new(NOW, 0));
+        let sched = scheduler_on(&clock);
+        let config = Config::default();
+
+        let mut col = Collection::new();
+        let japanese = col.add_deck("Japanese");
+        let vocab = col.add_deck("Japanese::Vocab");
+        let other = col.add_deck("Japanese Kanji");
+        let in_parent = col.add_card(japanese, Card::new(2)).unwrap();
+        let in_child = col.add_card(vocab, Card::new(1)).unwrap();
+        col.add_card(other, Card::new(0)).unwrap();
+
+        assert_eq!(
+            col.build_queues(japanese, &sched, &config).new,
+            vec![in_child, in_parent]
+        );
+        assert_eq!(col.build_queues(vocab, &sched, &config).new, vec![in_child]);
+        assert_eq!(
+            col.build_queues(DEFAULT_DECK_ID, &sched, &config),
+            Queues::default()
+        );
+    }
+
+    #[test]
+    fn test_daily_limits() {
+        let clock = Rc::new(FixedClock::new(NOW, 0));
+        let sched = scheduler_on(&clock);
+        let today = sched.day_today();
+        let config = Config {
+            new_per_day: 3,
+            reviews_per_day: 5,
+            ..Config::default()
+        };
+
+        let mut col = Collection::new();
+        let parent = col.add_deck("Parent");
+        let child = col.add_deck("Parent::Child");
+        for due in 0..4 {
+            col.add_card(child, review(today - due)).unwrap();
+            col.add_card(child, Card::new(due)).unwrap();
+        }
+        let queues = col.build_queues(parent, &sched, &config);
+        assert_eq!(queues.review.len(), 4);
+        // only one review left for new cards
+        assert_eq!(queues.new.len(), 1);
+
+        let ignore = Config {
+            new_ignore_review_limit: true,
+            ..config.clone()
+        };
+        assert_eq!(col.build_queues(parent, &sched, &ignore).new.len(), 3);
+
+        // Answering in the child counts for the parent too
+        let review_id = queues.review[0];
+        let new_id = queues.new[0];
+        col.answer_card(review_id, Choice::Ok, 0, &sched, &config)
+            .unwrap();
+        col.answer_card(new_id, Choice::Ok, 0, &sched, &config)
+            .unwrap();
+        let counts = DayCounts {
+            day: today,
+            new: 1,
+            review: 1,
+        };
+        assert_eq!(col.deck(parent).unwrap().studied, counts);
+        assert_eq!(col.deck(child).unwrap().studied, counts);
+        assert_eq!(col.deck(DEFAULT_DECK_ID).unwrap().studied.review, 0);
+
+        let queues = col.build_queues(parent, &sched, &config);
+        assert_eq!(queues.review.len(), 3);
+        assert!(queues.new.is_empty());
+        assert_eq!(col.build_queues(parent, &sched, &ignore).new.len(), 2);
+
+        // The counts start again the next day
+        clock.advance(86_400);
+        let tomorrow = scheduler_on(&clock);
+        assert_eq!(col.build_queues(parent, &tomorrow, &config).review.len(), 3);
+        assert_eq!(col.build_queues(parent, &tomorrow, &config).new.len(), 2);
+    }
+
+    #[test]
+    fn test_deck_configs() {
+        let clock = Rc::new(FixedClock::new(NOW, 0));
+        let sched = scheduler_on(&clock);
+        let config = Config {
+            new_per_day: 3,
+            ..Config::default()
+        };
+
+        let mut col = Collection::new();
+        let parent = col.add_deck("Parent");
+        let child = col.add_deck("Parent::Child");
+        let other = col.add_deck("Parent::Other");
+        for position in 0..4 {
+            col.add_card(parent, Card::new(position)).unwrap();
+            col.add_card(child, Card::new(position)).unwrap();
+            col.add_card(other, Card::new(position)).unwrap();
+        }
+        col.configs.insert(
+            7,
+            Config {
+                new_per_day: 1,
+                ..Config::default()
+            },
+        );
+        col.decks[2].config_id = Some(7);
+        assert_eq!(col.deck_config(child, &config).new_per_day, 1);
+        assert_eq!(col.deck_config(other, &config).new_per_day, 3);
+
+        // the child's own limit caps its share, and the parent's the total
+        let new = col.build_queues(parent, &sched, &config).new;
+        assert_eq!(new.len(), 3);
+        let in_deck = |deck| {
+            new.iter()
+                .filter(|id| col.card(**id).unwrap().deck_id == deck)
+                .count()
+        };
+        assert_eq!(in_deck(child), 1);
+        assert_eq!(col.build_queues(child, &sched, &config).new.len(), 1);
+        assert_eq!(col.build_queues(other, &sched, &config).new.len(), 3);
+    }
+
+    #[test]
+    fn test_bury_siblings() {
+        let clock = Rc::new(FixedClock::new(NOW, 0));
+        let sched = scheduler_on(&clock);
+        let today = sched.day_today();
+        let deck = DEFAULT_DECK_ID;
+        let sibling = |card: Card| Card { note_id: 9, ..card };
+
+        let mut col = Collection::new();
+        let answered = col.add_card(deck, sibling(Card::new(0))).unwrap();
+        let new = col.add_card(deck, sibling(Card::new(1))).unwrap();
+        let due = col.add_card(deck, sibling(review(today))).unwrap();
+        let later = col.add_card(deck, sibling(review(today + 3))).unwrap();
+        let day_learn = col
+            .add_card(deck, sibling(learn(CardQueue::DayLearn, today)))
+            .unwrap();
+        let other_note = col.add_card(deck, Card::new(2)).unwrap();
+        let queue = |col: &Collection, id| col.card(id).unwrap().card_queue;
+
+        // Nothing is buried by default
+        let mut unburied = col.clone();
+        unburied
+            .answer_card(answered, Choice::Ok, 0, &sched, &Config::default())
+            .unwrap();
+        assert_eq!(queue(&unburied, new), CardQueue::New);
+
+        let config = Config {
+            bury_new: true,
+            bury_reviews: true,
+            ..Config::default()
+        };
+        col.answer_card(answered, Choice::Ok, 0, &sched, &config)
+            .unwrap();
+        assert_eq!(queue(&col, answered), CardQueue::Learn);
+        assert_eq!(queue(&col, new), CardQueue::Buried);
+        assert_eq!(queue(&col, due), CardQueue::Buried);
+        assert_eq!(queue(&col, later), CardQueue::Review);
+        assert_eq!(queue(&col, day_learn), CardQueue::DayLearn);
+        assert_eq!(queue(&col, other_note), CardQueue::New);
+        assert_eq!(
+            col.build_queues(deck, &sched, &config).new,
+            vec![other_note]
+        );
+
+        // Back the next day, with the same scheduler
+        assert_eq!(col.unbury_all_for_new_day(&sched), 0);
+        clock.advance(86_400);
+        let queues = col.build_queues(deck, &sched, &config);
+        assert_eq!(col.unbury_all_for_new_day(&sched), 0);
+        assert_eq!(queue(&col, new), CardQueue::New);
+        assert_eq!(queue(&col, due), CardQueue::Review);
+        assert_eq!(col.card(due).unwrap().buried_day, None);
+        assert_eq!(col.card(due).unwrap().due, today);
+        assert!(queues.review.contains(&due));
+    }
+
+    #[test]
+    fn test_bury_siblings_by_deck() {
+        let clock = Rc::new(FixedClock::new(NOW, 0));
+        let sched = scheduler_on(&clock);
+        let config = Config {
+            bury_new: true,
+            ..Config::default()
+        };
+        let sibling = |card: Card| Card { note_id: 9, ..card };
+
+        let mut col = Collection::new();
+        let burying = DEFAULT_DECK_ID;
+        let keeping = col.add_deck("Keeping");
+        col.configs.insert(
+            7,
+            Config {
+                bury_new: false,
+                ..Config::default()
+            },
+        );
+        col.decks[1].config_id = Some(7);
+        let in_burying = col.add_card(burying, sibling(Card::new(0))).unwrap();
+        let in_keeping = col.add_card(keeping, sibling(Card::new(1))).unwrap();
+        let queue = |col: &Collection, id| col.card(id).unwrap().card_queue;
+
+        // answering in a deck that doesn't bury leaves the sibling alone
+        let mut kept = col.clone();
+        kept.answer_card(in_keeping, Choice::Ok, 0, &sched, &config)
+            .unwrap();
+        assert_eq!(queue(&kept, in_burying), CardQueue::New);
+
+        // and in one that does, buries it, whatever its own deck's options
+        col.answer_card(in_burying, Choice::Ok, 0, &sched, &config)
+            .unwrap();
+        assert_eq!(queue(&col, in_keeping), CardQueue::Buried);
+    }
+
+    #[test]
+    fn test_bury_by_hand() {
+        let clock = Rc::new(FixedClock::new(NOW, 0));
+        let sched = scheduler_on(&clock);
+        let config = Config::default();
+        let deck = DEFAULT_DECK_ID;
+
+        // buried outside the collection, which hasn't unburied anything yet
+        let mut card = review(sched.day_today());
+        sched.bury_card(&mut card);
+        assert_eq!(card.buried_day, Some(sched.day_today()));
+        let mut col = Collection::new();
+        let id = col.add_card(deck, card).unwrap();
+        // buried before there was a buried_day
+        let mut legacy = review(sched.day_today());
+        legacy.card_queue = CardQueue::Buried;
+        let legacy = col.add_card(deck, legacy).unwrap();
+
+        assert_eq!(col.build_queues(deck, &sched, &config).review, vec![legacy]);
+        assert_eq!(col.card(id).unwrap().card_queue, CardQueue::Buried);
+
+        clock.advance(86_400);
+        assert_eq!(
+            col.build_queues(deck, &sched, &config).review,
+            vec![id, legacy]
+        );
+    }
+
+    #[test]
+    fn test_add_card() {
+        let mut col = Collection::new();
+        assert!(col.add_card(42, Card::default()).is_err());
+
+        let card = Card {
+            id: 7,
+            ..Card::default()
+        };
+        assert_eq!(col.add_card(DEFAULT_DECK_ID, card.clone()), Ok(7));
+        assert!(col.add_card(DEFAULT_DECK_ID, card).is_err());
+        assert_eq!(col.add_card(DEFAULT_DECK_ID, Card::default()), Ok(8));
+
+        let clock = Rc::new(FixedClock::new(NOW, 0));
+        assert!(col
+            .answer_card(1, Choice::Ok, 0, &scheduler_on(&clock), &Config::default())
+            .is_err());
+    }
+}
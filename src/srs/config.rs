@@ -1,5 +1,14 @@
 use serde::{Deserialize, Serialize};
 
+use crate::error::{ConfigError, Error};
+use crate::srs::fsrs::DEFAULT_WEIGHTS;
+use crate::svc::clock::Clock;
+use crate::svc::timestamp::{check_timestamp, Timestamp, DEFAULT_ROLLOVER_HOUR};
+use crate::svc::timezone::Zone;
+
+// ease never drops below this when answering
+pub const MINIMUM_EASE: i32 = 1_300;
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
     pub learn_steps: Vec<f32>,
@@ -14,6 +23,70 @@ pub struct Config {
     pub graduating_interval_good: i32,
     pub graduating_interval_easy: i32,
     pub leech_threshold: i32,
+
+    // daily limits when studying a `Collection` deck
+    #[serde(default = "default_new_per_day")]
+    pub new_per_day: i32,
+    #[serde(default = "default_reviews_per_day")]
+    pub reviews_per_day: i32,
+    // by default new cards also use up the review limit
+    #[serde(default)]
+    pub new_ignore_review_limit: bool,
+
+    // bury the other cards of a note until tomorrow once one is answered
+    #[serde(default)]
+    pub bury_new: bool,
+    #[serde(default)]
+    pub bury_reviews: bool,
+    #[serde(default)]
+    pub bury_interday_learning: bool,
+
+    // local hour a new day starts
+    #[serde(default = "default_rollover_hour")]
+    pub rollover_hour: i32,
+    // IANA name or offset like "+05:30", instead of the clock's timezone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    // when the collection was created, and the offset west of UTC in minutes
+    // it was created at; days are counted from then instead of the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection_created: Option<i64>,
+    #[serde(default)]
+    pub collection_created_minutes_west: i32,
+
+    // name of a registered scheduler, eg "sm2" or "fsrs"
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
+
+    // only used by the FSRS scheduler
+    #[serde(default = "default_desired_retention")]
+    pub desired_retention: f32,
+    #[serde(default = "default_fsrs_weights")]
+    pub fsrs_weights: Vec<f32>,
+}
+
+fn default_rollover_hour() -> i32 {
+    DEFAULT_ROLLOVER_HOUR as i32
+}
+
+fn default_algorithm() -> String {
+    "sm2".to_string()
+}
+
+fn default_new_per_day() -> i32 {
+    20
+}
+
+fn default_reviews_per_day() -> i32 {
+    200
+}
+
+fn default_desired_retention() -> f32 {
+    0.9
+}
+
+fn default_fsrs_weights() -> Vec<f32> {
+    DEFAULT_WEIGHTS.to_vec()
 }
 
 impl Default for Config {
@@ -31,6 +104,300 @@ impl Default for Config {
             graduating_interval_good: 1,
             graduating_interval_easy: 4,
             leech_threshold: 8,
+            new_per_day: default_new_per_day(),
+            reviews_per_day: default_reviews_per_day(),
+            new_ignore_review_limit: false,
+            bury_new: false,
+            bury_reviews: false,
+            bury_interday_learning: false,
+            rollover_hour: default_rollover_hour(),
+            timezone: None,
+            collection_created: None,
+            collection_created_minutes_west: 0,
+            algorithm: default_algorithm(),
+            desired_retention: default_desired_retention(),
+            fsrs_weights: default_fsrs_weights(),
+        }
+    }
+}
+
+impl Config {
+    /// Return the config if every option is usable, otherwise every
+    /// offending option with the reason it was rejected.
+    pub fn validated(self) -> Result<Self, Error> {
+        let errors = self.validate();
+        if errors.is_empty() {
+            Ok(self)
+        } else {
+            Err(Error::InvalidConfig(errors))
+        }
+    }
+
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = vec![];
+
+        if self.learn_steps.is_empty() {
+            errors.push(ConfigError::new("learn_steps", "must not be empty"));
+        }
+        for (name, steps) in &[
+            ("learn_steps", &self.learn_steps),
+            ("relearn_steps", &self.relearn_steps),
+        ] {
+            if !steps.iter().all(|&step| positive(step)) {
+                errors.push(ConfigError::new(name, "must all be greater than 0"));
+            }
+        }
+
+        if self.initial_ease < MINIMUM_EASE {
+            errors.push(ConfigError::new(
+                "initial_ease",
+                format!("must be at least {}", MINIMUM_EASE),
+            ));
+        }
+
+        if !(self.easy_multiplier.is_finite() && self.easy_multiplier >= 1.0) {
+            errors.push(ConfigError::new("easy_multiplier", "must be at least 1"));
+        }
+        if !positive(self.hard_multiplier) {
+            errors.push(ConfigError::new(
+                "hard_multiplier",
+                "must be greater than 0",
+            ));
         }
+        if !(0.0..=1.0).contains(&self.lapse_multiplier) {
+            errors.push(ConfigError::new(
+                "lapse_multiplier",
+                "must be between 0 and 1",
+            ));
+        }
+        if !positive(self.interval_multiplier) {
+            errors.push(ConfigError::new(
+                "interval_multiplier",
+                "must be greater than 0",
+            ));
+        }
+
+        if self.minimum_review_interval < 1 {
+            errors.push(ConfigError::new(
+                "minimum_review_interval",
+                "must be at least 1",
+            ));
+        }
+        if self.maximum_review_interval < self.minimum_review_interval {
+            errors.push(ConfigError::new(
+                "maximum_review_interval",
+                "must not be less than minimum_review_interval",
+            ));
+        }
+
+        if self.graduating_interval_good < 1 {
+            errors.push(ConfigError::new(
+                "graduating_interval_good",
+                "must be at least 1",
+            ));
+        }
+        if self.graduating_interval_easy < self.graduating_interval_good {
+            errors.push(ConfigError::new(
+                "graduating_interval_easy",
+                "must not be less than graduating_interval_good",
+            ));
+        }
+
+        if self.leech_threshold < 0 {
+            errors.push(ConfigError::new("leech_threshold", "must not be negative"));
+        }
+
+        if self.new_per_day < 0 {
+            errors.push(ConfigError::new("new_per_day", "must not be negative"));
+        }
+        if self.reviews_per_day < 0 {
+            errors.push(ConfigError::new("reviews_per_day", "must not be negative"));
+        }
+
+        if !(0..=23).contains(&self.rollover_hour) {
+            errors.push(ConfigError::new(
+                "rollover_hour",
+                "must be between 0 and 23",
+            ));
+        }
+        if let Some(Err(reason)) = self.timezone.as_deref().map(Zone::parse) {
+            errors.push(ConfigError::new("timezone", reason));
+        }
+        if let Some(created) = self.collection_created {
+            if check_timestamp("collection_created", created).is_err() {
+                errors.push(ConfigError::new(
+                    "collection_created",
+                    "must be between years 1 and 9999",
+                ));
+            }
+        }
+        if self.collection_created_minutes_west.abs() >= 24 * 60 {
+            errors.push(ConfigError::new(
+                "collection_created_minutes_west",
+                "must be less than a day",
+            ));
+        }
+
+        if !(0.7..=0.99).contains(&self.desired_retention) {
+            errors.push(ConfigError::new(
+                "desired_retention",
+                "must be between 0.7 and 0.99",
+            ));
+        }
+        if self.fsrs_weights.len() != DEFAULT_WEIGHTS.len()
+            || !self.fsrs_weights.iter().all(|w| w.is_finite())
+        {
+            errors.push(ConfigError::new(
+                "fsrs_weights",
+                format!("must be {} numbers", DEFAULT_WEIGHTS.len()),
+            ));
+        }
+
+        errors
+    }
+}
+
+impl Config {
+    /// Timestamp of the next day rollover, in the configured timezone.
+    pub fn day_cut_off(&self, clock: &dyn Clock) -> i64 {
+        Timestamp::day_cut_off_at(clock, self.rollover(), self.zone().as_ref())
+    }
+
+    /// Today's day number, counted from the day the collection was created
+    /// when `collection_created` is set, and from the epoch otherwise.
+    pub fn day_today(&self, clock: &dyn Clock) -> i64 {
+        match self.collection_created {
+            Some(created) => Timestamp::days_elapsed_at(
+                clock,
+                created,
+                self.collection_created_minutes_west,
+                self.rollover(),
+                self.zone().as_ref(),
+            ),
+            None => self.day_cut_off(clock) / 86_400,
+        }
+    }
+
+    fn zone(&self) -> Option<Zone> {
+        self.timezone
+            .as_deref()
+            .and_then(|name| Zone::parse(name).ok())
+    }
+
+    fn rollover(&self) -> u8 {
+        self.rollover_hour.clamp(0, 23) as u8
+    }
+}
+
+fn positive(value: f32) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::svc::clock::FixedClock;
+
+    use super::*;
+
+    #[test]
+    fn test_default_is_valid() {
+        assert!(Config::default().validate().is_empty());
+        assert!(Config::default().validated().is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = Config {
+            learn_steps: vec![],
+            relearn_steps: vec![10.0, -1.0],
+            initial_ease: 1_000,
+            hard_multiplier: 0.0,
+            maximum_review_interval: 0,
+            new_per_day: -1,
+            rollover_hour: 24,
+            timezone: Some("Atlantis/Capital".to_string()),
+            collection_created: Some(i64::MIN),
+            ..Config::default()
+        };
+
+        let fields: Vec<String> = config.validate().into_iter().map(|e| e.field).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "learn_steps",
+                "relearn_steps",
+                "initial_ease",
+                "hard_multiplier",
+                "maximum_review_interval",
+                "new_per_day",
+                "rollover_hour",
+                "timezone",
+                "collection_created",
+            ]
+        );
+
+        match config.validated() {
+            Err(Error::InvalidConfig(errors)) => {
+                assert_eq!(
+                    errors[2],
+                    ConfigError::new("initial_ease", "must be at least 1300")
+                )
+            }
+            _ => panic!("expected config errors"),
+        }
+    }
+
+    #[test]
+    fn test_day_cut_off() {
+        // 2021-03-01 12:00:00 UTC, on a host clock 5 hours behind UTC
+        let clock = FixedClock::new(1_614_600_000, 300);
+        let config = Config::default();
+        // 04:00 on the 2nd at UTC-5
+        assert_eq!(config.day_cut_off(&clock), 1_614_675_600);
+
+        let config = Config {
+            rollover_hour: 0,
+            timezone: Some("Asia/Tokyo".to_string()),
+            ..Config::default()
+        };
+        // midnight on the 2nd in Tokyo
+        assert_eq!(config.day_cut_off(&clock), 1_614_610_800);
+
+        let config = Config {
+            timezone: Some("+01:00".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(config.day_cut_off(&clock), 1_614_654_000);
+    }
+
+    #[test]
+    fn test_day_today() {
+        // 2021-03-11 02:00 UTC: 12:00 in Sydney, and the 10th at 18:00 in
+        // Los Angeles
+        let clock = FixedClock::new(1_615_428_000, 480);
+        assert_eq!(Config::default().day_today(&clock), 18_697);
+
+        // created 2021-03-01 10:00 in Sydney
+        let config = Config {
+            collection_created: Some(1_614_556_800),
+            collection_created_minutes_west: -600,
+            ..Config::default()
+        };
+        assert_eq!(config.day_today(&clock), 9);
+
+        let config = Config {
+            timezone: Some("Australia/Sydney".to_string()),
+            ..config
+        };
+        assert_eq!(config.day_today(&clock), 10);
+    }
+
+    #[test]
+    fn test_relearn_steps_can_be_empty() {
+        let config = Config {
+            relearn_steps: vec![],
+            ..Config::default()
+        };
+        assert!(config.validate().is_empty());
     }
 }
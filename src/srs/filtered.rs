@@ -0,0 +1,379 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue};
+use crate::srs::collection::{Collection, Deck};
+use crate::srs::review_log::{ReviewKind, ReviewLog};
+use crate::srs::scheduler::{Choice, Sched};
+
+/// Which cards a filtered deck pulls in, always from a deck and its
+/// subdecks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Search {
+    /// Every card, eg to cram before an exam.
+    Deck { deck_id: i64 },
+    /// Review cards due in the next `days` days, to review ahead.
+    DueWithin { deck_id: i64, days: i64 },
+    /// New cards, to preview them.
+    New { deck_id: i64 },
+}
+
+impl Search {
+    fn deck_id(&self) -> i64 {
+        match self {
+            Search::Deck { deck_id }
+            | Search::DueWithin { deck_id, .. }
+            | Search::New { deck_id } => *deck_id,
+        }
+    }
+
+    fn matches(&self, card: &Card, today: i64) -> bool {
+        match self {
+            Search::Deck { .. } => true,
+            Search::DueWithin { days, .. } => {
+                card.card_queue == CardQueue::Review && card.due <= today + days
+            }
+            Search::New { .. } => card.card_queue == CardQueue::New,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Filter {
+    pub search: Search,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    // answers change the schedule, otherwise cards are only previewed
+    #[serde(default = "default_reschedule")]
+    pub reschedule: bool,
+    // seconds until a previewed card is shown again, 0 sends it home
+    #[serde(default = "default_preview_again_secs")]
+    pub preview_again_secs: i64,
+    #[serde(default = "default_preview_hard_secs")]
+    pub preview_hard_secs: i64,
+    #[serde(default)]
+    pub preview_ok_secs: i64,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+fn default_reschedule() -> bool {
+    true
+}
+
+fn default_preview_again_secs() -> i64 {
+    60
+}
+
+fn default_preview_hard_secs() -> i64 {
+    600
+}
+
+impl Filter {
+    pub fn new(search: Search) -> Self {
+        Self {
+            search,
+            limit: default_limit(),
+            reschedule: default_reschedule(),
+            preview_again_secs: default_preview_again_secs(),
+            preview_hard_secs: default_preview_hard_secs(),
+            preview_ok_secs: 0,
+        }
+    }
+}
+
+impl Collection {
+    /// Add an empty filtered deck; `rebuild_filtered_deck` fills it.
+    pub fn add_filtered_deck(&mut self, name: &str, filter: Filter) -> i64 {
+        let id = self.next_deck_id();
+        self.decks.push(Deck {
+            filter: Some(filter),
+            ..Deck::new(id, name)
+        });
+        id
+    }
+
+    fn filter(&self, deck_id: i64) -> Result<Filter, Error> {
+        self.deck(deck_id)
+            .and_then(|deck| deck.filter.clone())
+            .ok_or_else(|| Error::InvalidDeck(format!("deck {} is not a filtered deck", deck_id)))
+    }
+
+    /// Empty the filtered deck and move the cards matching its search into
+    /// it, remembering where they came from. Cards that are suspended,
+    /// buried or in another filtered deck are left alone. Returns the
+    /// number of cards moved.
+    pub fn rebuild_filtered_deck(
+        &mut self,
+        deck_id: i64,
+        sched: &dyn Sched,
+    ) -> Result<usize, Error> {
+        let filter = self.filter(deck_id)?;
+        self.empty_filtered_deck(deck_id)?;
+
+        let source = self.deck_ids(filter.search.deck_id());
+        let today = sched.day_today();
+        let mut matching: Vec<&mut Card> = self
+            .cards
+            .iter_mut()
+            .filter(|card| {
+                source.contains(&card.deck_id)
+                    && card.original_deck_id.is_none()
+                    && !matches!(card.card_queue, CardQueue::Suspended | CardQueue::Buried)
+                    && filter.search.matches(card, today)
+            })
+            .collect();
+        matching.sort_by_key(|card| (card.card_queue as i8, card.due, card.id));
+
+        let mut moved = 0;
+        for card in matching.into_iter().take(filter.limit) {
+            card.original_deck_id = Some(card.deck_id);
+            card.original_due = Some(card.due);
+            card.deck_id = deck_id;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// Send every card in the filtered deck back to its home deck.
+    pub fn empty_filtered_deck(&mut self, deck_id: i64) -> Result<(), Error> {
+        self.filter(deck_id)?;
+        for card in self.cards.iter_mut().filter(|card| card.deck_id == deck_id) {
+            return_home(card);
+        }
+        Ok(())
+    }
+}
+
+/// Answer a card in a filtered deck. Rescheduling decks answer it as usual
+/// and send it home once it's back in the review queue; previewing decks
+/// only show it again after a delay, leaving its schedule alone.
+pub(crate) fn answer_card(
+    card: &mut Card,
+    filter: &Filter,
+    choice: Choice,
+    time_taken: i32,
+    sched: &dyn Sched,
+) -> ReviewLog {
+    if filter.reschedule {
+        let early = card.card_queue == CardQueue::Review && card.due > sched.day_today();
+        let mut log = sched.answer_card(card, choice, time_taken);
+        // the scheduler owns the due date now
+        card.original_due = None;
+        if early {
+            log.review_kind = ReviewKind::Filtered;
+        }
+        if card.card_queue == CardQueue::Review {
+            return_home(card);
+        }
+        return log;
+    }
+
+    let before = card.clone();
+    let delay = match choice {
+        Choice::Again => filter.preview_again_secs,
+        Choice::Hard => filter.preview_hard_secs,
+        Choice::Ok => filter.preview_ok_secs,
+        Choice::Easy => 0,
+    };
+    if delay > 0 {
+        card.card_queue = CardQueue::Preview;
+        card.due = sched.now() + delay;
+    } else {
+        return_home(card);
+    }
+    let mut log = ReviewLog::new(&before, card, choice, sched.now(), time_taken);
+    log.review_kind = ReviewKind::Filtered;
+    log
+}
+
+fn return_home(card: &mut Card) {
+    if let Some(deck_id) = card.original_deck_id.take() {
+        card.deck_id = deck_id;
+    }
+    if let Some(due) = card.original_due.take() {
+        card.due = due;
+    }
+    if card.card_queue == CardQueue::Preview {
+        card.card_queue = card.queue_for_type();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::srs::card::CardType;
+    use crate::srs::collection::DEFAULT_DECK_ID;
+    use crate::srs::config::Config;
+    use crate::srs::test_util::{self, scheduler, NOW};
+
+    use super::*;
+
+    fn review(due: i64) -> Card {
+        Card {
+            interval: 10,
+            ..test_util::review(due)
+        }
+    }
+
+    #[test]
+    fn test_cram() {
+        let sched = scheduler();
+        let config = Config::default();
+        let today = sched.day_today();
+        let home = DEFAULT_DECK_ID;
+
+        let mut col = Collection::new();
+        let ahead = col.add_card(home, review(today + 5)).unwrap();
+        let due = col.add_card(home, review(today)).unwrap();
+        let new = col.add_card(home, Card::new(0)).unwrap();
+        let suspended = col
+            .add_card(
+                home,
+                Card {
+                    card_queue: CardQueue::Suspended,
+                    ..review(today)
+                },
+            )
+            .unwrap();
+
+        let cram = col.add_filtered_deck("Cram", Filter::new(Search::Deck { deck_id: home }));
+        assert_eq!(col.rebuild_filtered_deck(cram, &sched), Ok(3));
+        let card = col.card(ahead).unwrap();
+        assert_eq!(card.deck_id, cram);
+        assert_eq!(card.original_deck_id, Some(home));
+        assert_eq!(card.original_due, Some(today + 5));
+        assert_eq!(col.card(suspended).unwrap().deck_id, home);
+        assert!(col.build_queues(home, &sched, &config).review.is_empty());
+
+        // Reviews not due yet are shown too
+        let queues = col.build_queues(cram, &sched, &config);
+        assert_eq!(queues.review, vec![due, ahead]);
+        assert_eq!(queues.new, vec![new]);
+
+        // Reviewing ahead uses the early review interval
+        let log = col
+            .answer_card(ahead, Choice::Ok, 0, &sched, &config)
+            .unwrap();
+        assert_eq!(log.review_kind, ReviewKind::Filtered);
+        let card = col.card(ahead).unwrap();
+        assert_eq!(card.interval, 12);
+        assert_eq!(card.deck_id, home);
+        assert_eq!(card.original_deck_id, None);
+        assert_eq!(card.original_due, None);
+
+        let log = col
+            .answer_card(due, Choice::Ok, 0, &sched, &config)
+            .unwrap();
+        assert_eq!(log.review_kind, ReviewKind::Review);
+        assert_eq!(col.card(due).unwrap().deck_id, home);
+
+        // Learning cards stay until they graduate or the deck is emptied
+        col.answer_card(new, Choice::Ok, 0, &sched, &config)
+            .unwrap();
+        let card = col.card(new).unwrap();
+        assert_eq!(card.card_queue, CardQueue::Learn);
+        assert_eq!(card.deck_id, cram);
+
+        col.empty_filtered_deck(cram).unwrap();
+        let card = col.card(new).unwrap();
+        assert_eq!(card.deck_id, home);
+        assert_eq!(card.card_queue, CardQueue::Learn);
+        assert!(card.due > NOW);
+    }
+
+    #[test]
+    fn test_preview() {
+        let sched = scheduler();
+        let config = Config::default();
+        let home = DEFAULT_DECK_ID;
+
+        let mut col = Collection::new();
+        let new = col.add_card(home, Card::new(7)).unwrap();
+        col.add_card(home, review(sched.day_today())).unwrap();
+        let preview = col.add_filtered_deck(
+            "Preview",
+            Filter {
+                reschedule: false,
+                ..Filter::new(Search::New { deck_id: home })
+            },
+        );
+        assert_eq!(col.rebuild_filtered_deck(preview, &sched), Ok(1));
+
+        let log = col
+            .answer_card(new, Choice::Again, 0, &sched, &config)
+            .unwrap();
+        assert_eq!(log.review_kind, ReviewKind::Filtered);
+        let card = col.card(new).unwrap();
+        assert_eq!(card.card_queue, CardQueue::Preview);
+        assert_eq!(card.due, NOW + 60);
+        assert_eq!(card.reps, 0);
+        assert!(card.validate().is_ok());
+        assert_eq!(
+            col.build_queues(preview, &sched, &config).learn_ahead,
+            vec![new]
+        );
+
+        // Ok sends it home untouched
+        col.answer_card(new, Choice::Ok, 0, &sched, &config)
+            .unwrap();
+        let card = col.card(new).unwrap();
+        assert_eq!(card.card_queue, CardQueue::New);
+        assert_eq!(card.card_type, CardType::New);
+        assert_eq!(card.due, 7);
+        assert_eq!(card.deck_id, home);
+        assert_eq!(
+            col.build_queues(preview, &sched, &config),
+            Default::default()
+        );
+    }
+
+    #[test]
+    fn test_review_ahead() {
+        let sched = scheduler();
+        let today = sched.day_today();
+        let home = DEFAULT_DECK_ID;
+
+        let mut col = Collection::new();
+        for due in 0..6 {
+            col.add_card(home, review(today + due)).unwrap();
+        }
+        col.add_card(home, Card::new(0)).unwrap();
+        let filter = Filter {
+            limit: 3,
+            ..Filter::new(Search::DueWithin {
+                deck_id: home,
+                days: 4,
+            })
+        };
+        let ahead = col.add_filtered_deck("Ahead", filter);
+        assert_eq!(col.rebuild_filtered_deck(ahead, &sched), Ok(3));
+        let moved: Vec<i64> = col
+            .cards
+            .iter()
+            .filter(|card| card.deck_id == ahead)
+            .map(|card| card.due - today)
+            .collect();
+        assert_eq!(moved, vec![0, 1, 2]);
+
+        // Rebuilding starts over
+        assert_eq!(col.rebuild_filtered_deck(ahead, &sched), Ok(3));
+        col.empty_filtered_deck(ahead).unwrap();
+        assert!(col.cards.iter().all(|card| card.deck_id == home
+            && card.original_deck_id.is_none()
+            && card.original_due.is_none()));
+        assert_eq!(col.card(3).unwrap().due, today + 2);
+    }
+
+    #[test]
+    fn test_not_filtered() {
+        let mut col = Collection::new();
+        assert!(matches!(
+            col.rebuild_filtered_deck(DEFAULT_DECK_ID, &scheduler()),
+            Err(Error::InvalidDeck(_))
+        ));
+        assert!(col.empty_filtered_deck(42).is_err());
+    }
+}
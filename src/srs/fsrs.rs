@@ -0,0 +1,348 @@
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue, MemoryState};
+use crate::srs::config::{Config, MINIMUM_EASE};
+use crate::srs::review_log::ReviewLog;
+use crate::srs::scheduler::{Choice, Sched, Scheduler};
+use crate::svc::clock::Clock;
+
+/// FSRS-4.5 default weights.
+pub const DEFAULT_WEIGHTS: [f32; 17] = [
+    0.4872, 1.4003, 3.7145, 13.8206, 5.1618, 1.2298, 0.8975, 0.031, 1.6474, 0.1367, 1.0461, 2.1072,
+    0.0793, 0.3246, 1.587, 0.2272, 2.8755,
+];
+
+const DECAY: f32 = -0.5;
+const FACTOR: f32 = 19.0 / 81.0;
+
+/// FSRS scheduler. Learning steps, lapses, leeches and queues are handled
+/// exactly like `Scheduler`; only the day intervals of review cards come
+/// from each card's memory state and `Config::desired_retention`.
+pub struct Fsrs {
+    scheduler: Scheduler,
+}
+
+impl Fsrs {
+    pub fn new(config: Config, clock: Box<dyn Clock>) -> Result<Self, Error> {
+        Ok(Self {
+            scheduler: Scheduler::new(config, clock)?,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.scheduler.config
+    }
+
+    /// Days since the card was last reviewed. Learning cards count as
+    /// reviewed today.
+    fn elapsed_days(&self, card: &Card) -> f32 {
+        match card.card_queue {
+            CardQueue::Review => {
+                let last_review = card.due - card.interval as i64;
+                (self.scheduler.day_today() - last_review).max(0) as f32
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn update_memory_state(&self, before: &Card, card: &mut Card, choice: Choice) {
+        let weights = &self.config().fsrs_weights;
+        let elapsed = self.elapsed_days(before);
+        card.memory_state = Some(match before.memory_state {
+            // same day reviews don't change the memory state
+            Some(state) if elapsed == 0.0 => state,
+            Some(state) => next_memory_state(weights, state, elapsed, choice),
+            None if before.interval > 0 => {
+                next_memory_state(weights, memory_state_from_sm2(before), elapsed, choice)
+            }
+            None => initial_memory_state(weights, choice),
+        });
+    }
+
+    fn review_interval(&self, card: &Card, fuzzy: bool) -> i32 {
+        let stability = card.memory_state.map_or(1.0, |state| state.stability);
+        let ideal = next_interval(stability, self.config().desired_retention).round() as i32;
+        let interval = if fuzzy {
+            Scheduler::fuzz_interval(card, ideal.max(1))
+        } else {
+            ideal
+        };
+        interval.max(1).min(self.config().maximum_review_interval)
+    }
+
+    fn schedule(&self, before: &Card, card: &mut Card, choice: Choice, fuzzy: bool) {
+        self.update_memory_state(before, card, choice);
+        if card.card_queue == CardQueue::Review {
+            card.interval = self.review_interval(card, fuzzy);
+            card.due = self.scheduler.day_today() + card.interval as i64;
+        }
+    }
+}
+
+impl Sched for Fsrs {
+    fn next_interval(&self, card: &Card, choice: Choice) -> i64 {
+        let mut preview = card.clone();
+        self.scheduler.answer(&mut preview, choice);
+        if preview.card_queue == CardQueue::Review {
+            self.schedule(card, &mut preview, choice, false);
+            preview.interval as i64 * 86_400
+        } else {
+            self.scheduler.next_interval(card, choice)
+        }
+    }
+
+    fn answer_card(&self, card: &mut Card, choice: Choice, time_taken: i32) -> ReviewLog {
+        let before = card.clone();
+        self.scheduler.answer(card, choice);
+        self.schedule(&before, card, choice, true);
+        ReviewLog::new(&before, card, choice, self.scheduler.now(), time_taken)
+    }
+
+    fn schedule_card_as_new(&self, card: &mut Card) {
+        self.scheduler.schedule_card_as_new(card);
+        card.memory_state = None;
+    }
+
+    fn schedule_card_as_review(&self, card: &mut Card, min_days: i32, max_days: i32) {
+        self.scheduler
+            .schedule_card_as_review(card, min_days, max_days)
+    }
+
+    fn now(&self) -> i64 {
+        self.scheduler.now()
+    }
+
+    fn day_cut_off(&self) -> i64 {
+        self.scheduler.day_cut_off()
+    }
+
+    fn day_today(&self) -> i64 {
+        self.scheduler.day_today()
+    }
+}
+
+/// Probability of recalling a card `elapsed_days` after its last review.
+pub fn retrievability(elapsed_days: f32, stability: f32) -> f32 {
+    (1.0 + FACTOR * elapsed_days / stability).powf(DECAY)
+}
+
+/// Days until recall probability drops to `desired_retention`.
+pub fn next_interval(stability: f32, desired_retention: f32) -> f32 {
+    stability / FACTOR * (desired_retention.powf(1.0 / DECAY) - 1.0)
+}
+
+pub fn initial_memory_state(w: &[f32], choice: Choice) -> MemoryState {
+    MemoryState {
+        stability: w[choice as usize - 1].max(0.1),
+        difficulty: initial_difficulty(w, choice),
+    }
+}
+
+pub fn next_memory_state(
+    w: &[f32],
+    state: MemoryState,
+    elapsed_days: f32,
+    choice: Choice,
+) -> MemoryState {
+    let MemoryState {
+        stability,
+        difficulty,
+    } = state;
+    let r = retrievability(elapsed_days, stability);
+    let stability = match choice {
+        Choice::Again => forget_stability(w, difficulty, stability, r),
+        _ => recall_stability(w, difficulty, stability, r, choice),
+    };
+    MemoryState {
+        stability: stability.max(0.1),
+        difficulty: next_difficulty(w, difficulty, choice),
+    }
+}
+
+/// Rough memory state for a card that was scheduled by SM-2 so far.
+pub fn memory_state_from_sm2(card: &Card) -> MemoryState {
+    let ease = card.ease_factor.max(MINIMUM_EASE) as f32;
+    MemoryState {
+        stability: card.interval.max(1) as f32,
+        difficulty: (10.0 - (ease - MINIMUM_EASE as f32) / 240.0).clamp(1.0, 10.0),
+    }
+}
+
+fn grade(choice: Choice) -> f32 {
+    choice as i8 as f32
+}
+
+fn initial_difficulty(w: &[f32], choice: Choice) -> f32 {
+    (w[4] - (grade(choice) - 3.0) * w[5]).clamp(1.0, 10.0)
+}
+
+fn next_difficulty(w: &[f32], difficulty: f32, choice: Choice) -> f32 {
+    let next = difficulty - w[6] * (grade(choice) - 3.0);
+    // mean reversion towards the difficulty of a first Ok
+    (w[7] * initial_difficulty(w, Choice::Ok) + (1.0 - w[7]) * next).clamp(1.0, 10.0)
+}
+
+fn recall_stability(w: &[f32], difficulty: f32, stability: f32, r: f32, choice: Choice) -> f32 {
+    let hard_penalty = if choice == Choice::Hard { w[15] } else { 1.0 };
+    let easy_bonus = if choice == Choice::Easy { w[16] } else { 1.0 };
+    stability
+        * (1.0
+            + w[8].exp()
+                * (11.0 - difficulty)
+                * stability.powf(-w[9])
+                * ((w[10] * (1.0 - r)).exp() - 1.0)
+                * hard_penalty
+                * easy_bonus)
+}
+
+fn forget_stability(w: &[f32], difficulty: f32, stability: f32, r: f32) -> f32 {
+    let forget = w[11]
+        * difficulty.powf(-w[12])
+        * ((stability + 1.0).powf(w[13]) - 1.0)
+        * (w[14] * (1.0 - r)).exp();
+    forget.min(stability)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::srs::card::CardType;
+    use crate::srs::registry::Registry;
+    use crate::srs::test_util::NOW;
+    use crate::svc::clock::FixedClock;
+
+    use super::*;
+
+    fn fsrs() -> Fsrs {
+        Fsrs::new(Config::default(), Box::new(FixedClock::new(NOW, 0))).unwrap()
+    }
+
+    #[test]
+    fn test_formulas() {
+        // at 90% retention the interval equals the stability
+        assert!((next_interval(10.0, 0.9) - 10.0).abs() < 0.01);
+        assert!((retrievability(10.0, 10.0) - 0.9).abs() < 0.001);
+        assert_eq!(retrievability(0.0, 10.0), 1.0);
+
+        let w = &DEFAULT_WEIGHTS;
+        let state = initial_memory_state(w, Choice::Ok);
+        assert_eq!(state.stability, w[2]);
+        assert_eq!(state.difficulty, w[4]);
+
+        let passed = next_memory_state(w, state, 4.0, Choice::Ok);
+        assert!(passed.stability > state.stability);
+        let failed = next_memory_state(w, state, 4.0, Choice::Again);
+        assert!(failed.stability < state.stability);
+        assert!(failed.difficulty > state.difficulty);
+        let easy = next_memory_state(w, state, 4.0, Choice::Easy);
+        assert!(easy.stability > passed.stability);
+        assert!(easy.difficulty < state.difficulty);
+    }
+
+    #[test]
+    fn test_new_card() {
+        let fsrs = fsrs();
+        let mut card = Card::default();
+
+        fsrs.answer_card(&mut card, Choice::Easy, 0);
+        assert_eq!(card.card_type, CardType::Review);
+        assert_eq!(card.card_queue, CardQueue::Review);
+        let stability = card.memory_state.unwrap().stability;
+        assert_eq!(stability, DEFAULT_WEIGHTS[3]);
+        let (min, max) = Scheduler::fuzz_interval_range(14);
+        assert!(card.interval >= min && card.interval <= max);
+        assert_eq!(card.due, fsrs.day_today() + card.interval as i64);
+    }
+
+    #[test]
+    fn test_learning_steps() {
+        let fsrs = fsrs();
+        let mut card = Card::default();
+
+        // Learning steps work like SM-2, first answer sets the memory
+        fsrs.answer_card(&mut card, Choice::Ok, 0);
+        assert_eq!(card.card_queue, CardQueue::Learn);
+        let state = card.memory_state.unwrap();
+        assert_eq!(state.stability, DEFAULT_WEIGHTS[2]);
+
+        // and same day steps keep it
+        assert_eq!(fsrs.next_interval(&card, Choice::Ok), 4 * 86_400);
+        fsrs.answer_card(&mut card, Choice::Ok, 0);
+        assert_eq!(card.card_queue, CardQueue::Review);
+        assert_eq!(card.memory_state.unwrap(), state);
+    }
+
+    #[test]
+    fn test_review() {
+        let fsrs = fsrs();
+        let card = Card {
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: fsrs.day_today(),
+            interval: 10,
+            ease_factor: 2_500,
+            memory_state: Some(MemoryState {
+                stability: 10.0,
+                difficulty: 5.0,
+            }),
+            ..Card::default()
+        };
+
+        let hard = fsrs.next_interval(&card, Choice::Hard);
+        let ok = fsrs.next_interval(&card, Choice::Ok);
+        let easy = fsrs.next_interval(&card, Choice::Easy);
+        assert!(hard < ok && ok < easy);
+        assert!(hard > 10 * 86_400);
+
+        let mut answered = card.clone();
+        fsrs.answer_card(&mut answered, Choice::Ok, 0);
+        let stability = answered.memory_state.unwrap().stability;
+        assert!(stability > 10.0);
+        let (min, max) = Scheduler::fuzz_interval_range(stability.round() as i32);
+        assert!(answered.interval >= min && answered.interval <= max);
+
+        // Lapse goes to relearning with a lower stability
+        let mut answered = card.clone();
+        fsrs.answer_card(&mut answered, Choice::Again, 0);
+        assert_eq!(answered.card_type, CardType::Relearn);
+        assert!(answered.memory_state.unwrap().stability < 10.0);
+    }
+
+    #[test]
+    fn test_sm2_card() {
+        let fsrs = fsrs();
+        let mut card = Card {
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: fsrs.day_today(),
+            interval: 30,
+            ease_factor: 2_500,
+            ..Card::default()
+        };
+
+        fsrs.answer_card(&mut card, Choice::Ok, 0);
+        let state = card.memory_state.unwrap();
+        assert!(state.stability > 30.0);
+        assert!((1.0..=10.0).contains(&state.difficulty));
+    }
+
+    #[test]
+    fn test_selected_by_config() {
+        let registry = Registry::default();
+        let config = Config {
+            algorithm: "fsrs".to_string(),
+            ..Config::default()
+        };
+        let sched = registry
+            .build(config, Box::new(FixedClock::new(NOW, 0)))
+            .unwrap();
+        let mut card = Card::default();
+        sched.answer_card(&mut card, Choice::Ok, 0);
+        assert!(card.memory_state.is_some());
+
+        let sched = registry
+            .build(Config::default(), Box::new(FixedClock::new(NOW, 0)))
+            .unwrap();
+        let mut card = Card::default();
+        sched.answer_card(&mut card, Choice::Ok, 0);
+        assert!(card.memory_state.is_none());
+    }
+}
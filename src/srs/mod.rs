@@ -1,3 +1,14 @@
 pub mod card;
+pub mod collection;
 pub mod config;
+pub mod filtered;
+pub mod fsrs;
+pub mod optimizer;
+pub mod registry;
+pub mod review_log;
 pub mod scheduler;
+pub mod simulator;
+pub mod supermemo;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod undo;
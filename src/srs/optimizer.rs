@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use serde::Serialize;
+
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue, MemoryState};
+use crate::srs::config::Config;
+use crate::srs::fsrs::{initial_memory_state, next_memory_state, retrievability};
+use crate::srs::review_log::ReviewLog;
+use crate::srs::scheduler::{Choice, Sched, Scheduler};
+use crate::svc::clock::FixedClock;
+
+// SM-2 intervals are assumed to be the time until recall drops to this
+const SM2_RETENTION: f32 = 0.9;
+
+const MAX_PASSES: usize = 200;
+
+const SM2_BOUNDS: [(f32, f32); 4] = [
+    // initial_ease, easy_multiplier, hard_multiplier, interval_multiplier
+    (1_300.0, 5_000.0),
+    (1.0, 3.0),
+    (0.5, 2.0),
+    (0.5, 3.0),
+];
+
+const FSRS_BOUNDS: [(f32, f32); 17] = [
+    (0.1, 100.0),
+    (0.1, 100.0),
+    (0.1, 100.0),
+    (0.1, 100.0),
+    (1.0, 10.0),
+    (0.1, 5.0),
+    (0.1, 5.0),
+    (0.0, 0.75),
+    (0.0, 4.0),
+    (0.0, 0.8),
+    (0.01, 3.0),
+    (0.5, 5.0),
+    (0.01, 0.2),
+    (0.01, 0.9),
+    (0.01, 3.0),
+    (0.0, 1.0),
+    (1.0, 6.0),
+];
+
+/// How well a config predicts whether cards were recalled.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Metrics {
+    pub log_loss: f32,
+    pub rmse: f32,
+    // number of reviews that were predicted
+    pub reviews: usize,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Optimized {
+    pub config: Config,
+    pub before: Metrics,
+    pub after: Metrics,
+}
+
+/// Fit `config` to a review history. FSRS configs get new `fsrs_weights`,
+/// other configs new `initial_ease`, `easy_multiplier`, `hard_multiplier`
+/// and `interval_multiplier`.
+///
+/// Only reviews at least a day after the previous one are predicted; same
+/// day learning steps just update the card.
+pub fn optimize(config: &Config, logs: &[ReviewLog]) -> Result<Optimized, Error> {
+    let config = config.clone().validated()?;
+    let history = group_by_card(logs)?;
+
+    let fsrs = config.algorithm == "fsrs";
+    let (start, bounds): (Vec<f32>, &[(f32, f32)]) = if fsrs {
+        (config.fsrs_weights.clone(), &FSRS_BOUNDS)
+    } else {
+        (sm2_params(&config), &SM2_BOUNDS)
+    };
+    let evaluate = |params: &[f32]| {
+        if fsrs {
+            fsrs_metrics(params, &history)
+        } else {
+            sm2_metrics(&with_sm2_params(&config, params), &history)
+        }
+    };
+
+    let before = evaluate(&start);
+    let params = coordinate_search(start, bounds, |params| evaluate(params).log_loss);
+    let after = evaluate(&params);
+
+    let config = if fsrs {
+        Config {
+            fsrs_weights: params,
+            ..config
+        }
+    } else {
+        with_sm2_params(&config, &params)
+    };
+    Ok(Optimized {
+        config,
+        before,
+        after,
+    })
+}
+
+/// Each card's reviews, oldest first.
+fn group_by_card(logs: &[ReviewLog]) -> Result<Vec<Vec<&ReviewLog>>, Error> {
+    let mut cards: BTreeMap<i64, Vec<&ReviewLog>> = BTreeMap::new();
+    for log in logs {
+        if log.time_taken < 0 {
+            return Err(Error::InvalidReviewLog(format!(
+                "card {} has negative time_taken",
+                log.card_id
+            )));
+        }
+        cards.entry(log.card_id).or_default().push(log);
+    }
+    Ok(cards
+        .into_values()
+        .map(|mut reviews| {
+            reviews.sort_by_key(|log| log.timestamp);
+            reviews
+        })
+        .collect())
+}
+
+fn sm2_params(config: &Config) -> Vec<f32> {
+    vec![
+        config.initial_ease as f32,
+        config.easy_multiplier,
+        config.hard_multiplier,
+        config.interval_multiplier,
+    ]
+}
+
+fn with_sm2_params(config: &Config, params: &[f32]) -> Config {
+    Config {
+        initial_ease: params[0].round() as i32,
+        easy_multiplier: params[1],
+        hard_multiplier: params[2],
+        interval_multiplier: params[3],
+        ..config.clone()
+    }
+}
+
+/// Replay every card through `Scheduler` with the config, predicting each
+/// review from the interval the config would have given.
+fn sm2_metrics(config: &Config, history: &[Vec<&ReviewLog>]) -> Metrics {
+    let clock = Rc::new(FixedClock::new(0, 0));
+    let scheduler = match Scheduler::new(config.clone(), Box::new(clock.clone())) {
+        Ok(scheduler) => scheduler,
+        Err(_) => return Metrics::worst(),
+    };
+
+    let mut predictions = vec![];
+    for reviews in history {
+        let mut card = Card {
+            id: reviews[0].card_id,
+            ..Card::default()
+        };
+        for log in reviews {
+            clock.set(log.timestamp);
+
+            if card.card_queue == CardQueue::Suspended {
+                scheduler.unsuspend_card(&mut card);
+            }
+            if card.card_queue == CardQueue::Review && card.interval > 0 {
+                let elapsed = scheduler.day_today() - (card.due - card.interval as i64);
+                if elapsed >= 1 {
+                    let p = SM2_RETENTION.powf(elapsed as f32 / card.interval as f32);
+                    predictions.push((p, log.choice != Choice::Again));
+                }
+            }
+            scheduler.answer_card(&mut card, log.choice, 0);
+        }
+    }
+    Metrics::new(&predictions)
+}
+
+fn fsrs_metrics(weights: &[f32], history: &[Vec<&ReviewLog>]) -> Metrics {
+    let mut predictions = vec![];
+    for reviews in history {
+        let mut state: Option<MemoryState> = None;
+        let mut last_review = reviews[0].timestamp;
+        for log in reviews {
+            let elapsed = ((log.timestamp - last_review) / 86_400) as f32;
+            state = Some(match state {
+                None => initial_memory_state(weights, log.choice),
+                Some(state) if elapsed < 1.0 => state,
+                Some(state) => {
+                    let p = retrievability(elapsed, state.stability);
+                    predictions.push((p, log.choice != Choice::Again));
+                    next_memory_state(weights, state, elapsed, log.choice)
+                }
+            });
+            if elapsed >= 1.0 {
+                last_review = log.timestamp;
+            }
+        }
+    }
+    Metrics::new(&predictions)
+}
+
+impl Metrics {
+    fn new(predictions: &[(f32, bool)]) -> Self {
+        if predictions.is_empty() {
+            return Self {
+                log_loss: 0.0,
+                rmse: 0.0,
+                reviews: 0,
+            };
+        }
+
+        let count = predictions.len() as f32;
+        let mut log_loss = 0.0;
+        let mut squared = 0.0;
+        for &(p, recalled) in predictions {
+            let p = p.clamp(0.0001, 0.9999);
+            let y = if recalled { 1.0 } else { 0.0 };
+            log_loss -= y * p.ln() + (1.0 - y) * (1.0 - p).ln();
+            squared += (p - y) * (p - y);
+        }
+        Self {
+            log_loss: log_loss / count,
+            rmse: (squared / count).sqrt(),
+            reviews: predictions.len(),
+        }
+    }
+
+    fn worst() -> Self {
+        Self {
+            log_loss: f32::INFINITY,
+            rmse: 1.0,
+            reviews: 0,
+        }
+    }
+}
+
+/// Move one parameter at a time while the loss improves, halving the step
+/// sizes whenever no move helps.
+fn coordinate_search(
+    mut params: Vec<f32>,
+    bounds: &[(f32, f32)],
+    loss: impl Fn(&[f32]) -> f32,
+) -> Vec<f32> {
+    let mut steps: Vec<f32> = bounds.iter().map(|(lo, hi)| (hi - lo) / 10.0).collect();
+    let mut best = loss(&params);
+
+    for _ in 0..MAX_PASSES {
+        let mut improved = false;
+        for i in 0..params.len() {
+            for direction in &[1.0, -1.0] {
+                let mut candidate = params.clone();
+                let (lo, hi) = bounds[i];
+                candidate[i] = (params[i] + direction * steps[i]).clamp(lo, hi);
+                if candidate[i] == params[i] {
+                    continue;
+                }
+                let value = loss(&candidate);
+                if value < best {
+                    best = value;
+                    params = candidate;
+                    improved = true;
+                    break;
+                }
+            }
+        }
+
+        if !improved {
+            steps.iter_mut().for_each(|step| *step /= 2.0);
+            let done = steps
+                .iter()
+                .zip(bounds)
+                .all(|(step, (lo, hi))| *step < (hi - lo) / 1_000.0);
+            if done {
+                break;
+            }
+        }
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::srs::card::CardType;
+    use crate::srs::review_log::ReviewKind;
+    use crate::srs::test_util::NOW;
+
+    use super::*;
+
+    fn log(card_id: i64, day: i64, choice: Choice) -> ReviewLog {
+        ReviewLog {
+            card_id,
+            timestamp: NOW + day * 86_400,
+            choice,
+            interval: 0,
+            last_interval: 0,
+            ease_factor: 0,
+            last_ease_factor: 0,
+            card_type: CardType::Review,
+            time_taken: 0,
+            review_kind: ReviewKind::Review,
+        }
+    }
+
+    // Cards that are always remembered, even when reviewed late
+    fn easy_history() -> Vec<ReviewLog> {
+        let mut logs = vec![];
+        for id in 1..=10 {
+            logs.push(log(id, 0, Choice::Ok));
+            logs.push(log(id, 0, Choice::Ok));
+            for day in &[3, 12, 40, 120] {
+                logs.push(log(id, *day, Choice::Ok));
+            }
+        }
+        logs
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new(&[(0.9, true), (0.9, false)]);
+        assert_eq!(metrics.reviews, 2);
+        assert!((metrics.log_loss - 1.2039728).abs() < 1e-5);
+        assert!((metrics.rmse - 0.6403124).abs() < 1e-5);
+        assert_eq!(Metrics::new(&[]).reviews, 0);
+    }
+
+    #[test]
+    fn test_optimize_sm2() {
+        let config = Config::default();
+        let result = optimize(&config, &easy_history()).unwrap();
+        assert_eq!(result.before.reviews, 40);
+        assert!(result.after.log_loss < result.before.log_loss);
+        assert!(result.config.interval_multiplier > config.interval_multiplier);
+        assert!(result.config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_optimize_fsrs() {
+        let config = Config {
+            algorithm: "fsrs".to_string(),
+            ..Config::default()
+        };
+        let result = optimize(&config, &easy_history()).unwrap();
+        assert_eq!(result.before.reviews, 40);
+        assert!(result.after.log_loss < result.before.log_loss);
+        assert!(result.config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_log() {
+        let mut logs = easy_history();
+        logs[3].time_taken = -1;
+        assert!(matches!(
+            optimize(&Config::default(), &logs),
+            Err(Error::InvalidReviewLog(_))
+        ));
+    }
+}
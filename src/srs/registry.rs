@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+
+use crate::error::{ConfigError, Error};
+use crate::srs::config::Config;
+use crate::srs::fsrs::Fsrs;
+use crate::srs::scheduler::{Sched, Scheduler};
+use crate::srs::supermemo::SuperMemo2;
+use crate::svc::clock::Clock;
+
+pub type SchedulerFactory = fn(Config, Box<dyn Clock>) -> Result<Box<dyn Sched>, Error>;
+
+/// Scheduling algorithms by name, so `Config::algorithm` can pick one.
+pub struct Registry {
+    factories: BTreeMap<String, SchedulerFactory>,
+}
+
+impl Registry {
+    pub fn empty() -> Self {
+        Self {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// Add an algorithm, replacing any with the same name.
+    pub fn register(&mut self, name: &str, factory: SchedulerFactory) {
+        self.factories.insert(name.to_string(), factory);
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.factories.keys().map(String::as_str).collect()
+    }
+
+    /// Build the scheduler named by `config.algorithm`.
+    pub fn build(&self, config: Config, clock: Box<dyn Clock>) -> Result<Box<dyn Sched>, Error> {
+        match self.factories.get(&config.algorithm) {
+            Some(factory) => factory(config, clock),
+            None => Err(Error::InvalidConfig(vec![ConfigError::new(
+                "algorithm",
+                format!("must be one of {}", self.names().join(", ")),
+            )])),
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register("sm2", |config, clock| {
+            Ok(Box::new(Scheduler::new(config, clock)?))
+        });
+        registry.register("fsrs", |config, clock| {
+            Ok(Box::new(Fsrs::new(config, clock)?))
+        });
+        registry.register("supermemo2", |config, clock| {
+            Ok(Box::new(SuperMemo2::new(config, clock)?))
+        });
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::srs::card::{Card, CardQueue, CardType};
+    use crate::srs::review_log::ReviewLog;
+    use crate::srs::scheduler::Choice;
+    use crate::srs::test_util::NOW;
+    use crate::svc::clock::FixedClock;
+    use crate::svc::timestamp::Timestamp;
+
+    use super::*;
+
+    /// Leitner boxes, keeping the box number in the card's scheduler state.
+    struct Leitner {
+        clock: Box<dyn Clock>,
+    }
+
+    impl Leitner {
+        fn boxed(_config: Config, clock: Box<dyn Clock>) -> Result<Box<dyn Sched>, Error> {
+            Ok(Box::new(Self { clock }))
+        }
+
+        fn next_box(card: &Card, choice: Choice) -> i64 {
+            let current = card
+                .scheduler_state
+                .as_ref()
+                .and_then(|state| state["box"].as_i64())
+                .unwrap_or(0);
+            match choice {
+                Choice::Again => 1,
+                _ => (current + 1).min(5),
+            }
+        }
+    }
+
+    impl Sched for Leitner {
+        fn next_interval(&self, card: &Card, choice: Choice) -> i64 {
+            (1 << (Self::next_box(card, choice) - 1)) * 86_400
+        }
+
+        fn answer_card(&self, card: &mut Card, choice: Choice, time_taken: i32) -> ReviewLog {
+            let before = card.clone();
+            let next_box = Self::next_box(card, choice);
+            card.reps += 1;
+            card.interval = 1 << (next_box - 1);
+            card.due = self.day_today() + card.interval as i64;
+            card.card_type = CardType::Review;
+            card.card_queue = CardQueue::Review;
+            card.scheduler_state = Some(json!({ "box": next_box }));
+            ReviewLog::new(&before, card, choice, self.clock.now(), time_taken)
+        }
+
+        fn schedule_card_as_new(&self, card: &mut Card) {
+            card.schedule_as_new(0, 0);
+            card.scheduler_state = None;
+        }
+
+        fn schedule_card_as_review(&self, card: &mut Card, min_days: i32, _max_days: i32) {
+            card.schedule_as_review(min_days, self.day_today(), 0);
+        }
+
+        fn now(&self) -> i64 {
+            self.clock.now()
+        }
+
+        fn day_cut_off(&self) -> i64 {
+            Timestamp::day_cut_off(self.clock.as_ref())
+        }
+
+        fn day_today(&self) -> i64 {
+            self.day_cut_off() / 86_400
+        }
+    }
+
+    #[test]
+    fn test_builtin() {
+        let registry = Registry::default();
+        assert_eq!(registry.names(), vec!["fsrs", "sm2", "supermemo2"]);
+
+        let config = Config {
+            algorithm: "sm5".to_string(),
+            ..Config::default()
+        };
+        let result = registry.build(config, Box::new(FixedClock::new(NOW, 0)));
+        assert_eq!(
+            result.err(),
+            Some(Error::InvalidConfig(vec![ConfigError::new(
+                "algorithm",
+                "must be one of fsrs, sm2, supermemo2"
+            )]))
+        );
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = Registry::default();
+        registry.register("leitner", Leitner::boxed);
+
+        let config = Config {
+            algorithm: "leitner".to_string(),
+            ..Config::default()
+        };
+        let sched = registry
+            .build(config, Box::new(FixedClock::new(NOW, 0)))
+            .unwrap();
+
+        let mut card = Card::default();
+        sched.answer_card(&mut card, Choice::Ok, 0);
+        sched.answer_card(&mut card, Choice::Ok, 0);
+        assert_eq!(card.scheduler_state, Some(json!({ "box": 2 })));
+        assert_eq!(card.interval, 2);
+        assert_eq!(sched.next_interval_string(&card, Choice::Ok), "4d");
+
+        // queue handling comes with the trait
+        sched.suspend_card(&mut card);
+        sched.unsuspend_card(&mut card);
+        assert_eq!(card.card_queue, CardQueue::Review);
+
+        sched.answer_card(&mut card, Choice::Again, 0);
+        assert_eq!(card.scheduler_state, Some(json!({ "box": 1 })));
+    }
+}
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+
+use crate::srs::card::{Card, CardType};
+use crate::srs::scheduler::Choice;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[repr(i8)]
+pub enum ReviewKind {
+    Learn = 0,
+    Review = 1,
+    Relearn = 2,
+    // previewed, or reviewed early, in a filtered deck
+    Filtered = 3,
+}
+
+/// What happened when a card was answered.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ReviewLog {
+    pub card_id: i64,
+    // seconds since epoch
+    pub timestamp: i64,
+    pub choice: Choice,
+    pub interval: i32,
+    pub last_interval: i32,
+    pub ease_factor: i32,
+    pub last_ease_factor: i32,
+    // type of the card before it was answered
+    pub card_type: CardType,
+    // milliseconds spent on the card
+    pub time_taken: i32,
+    pub review_kind: ReviewKind,
+}
+
+impl ReviewLog {
+    pub fn new(
+        before: &Card,
+        after: &Card,
+        choice: Choice,
+        timestamp: i64,
+        time_taken: i32,
+    ) -> Self {
+        Self {
+            card_id: after.id,
+            timestamp,
+            choice,
+            interval: after.interval,
+            last_interval: before.interval,
+            ease_factor: after.ease_factor,
+            last_ease_factor: before.ease_factor,
+            card_type: before.card_type,
+            time_taken,
+            review_kind: ReviewKind::for_card(before),
+        }
+    }
+}
+
+impl ReviewKind {
+    pub fn for_card(card: &Card) -> Self {
+        match card.card_type {
+            CardType::New | CardType::Learn => ReviewKind::Learn,
+            CardType::Review => ReviewKind::Review,
+            CardType::Relearn => ReviewKind::Relearn,
+        }
+    }
+}
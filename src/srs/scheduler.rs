@@ -1,16 +1,20 @@
 use std::cmp::{max, min};
 
 use rand::distributions::{Distribution, Uniform};
-use rand::Rng;
-
-use crate::srs::card::{Card, CardQueue, CardType};
-use crate::srs::config::Config;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue, CardType, LearningState, StepKind};
+use crate::srs::config::{Config, MINIMUM_EASE};
+use crate::srs::review_log::ReviewLog;
+use crate::srs::undo::UndoToken;
+use crate::svc::clock::Clock;
 use crate::svc::timespan::answer_button_time;
-use crate::svc::timestamp::Timestamp;
 
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize_repr, Deserialize_repr)]
 #[repr(i8)]
 pub enum Choice {
     Again = 1,
@@ -19,44 +23,108 @@ pub enum Choice {
     Easy = 4,
 }
 
+/// A scheduling algorithm. Only answering and rescheduling are algorithm
+/// specific; burying and suspending just move cards between queues.
+/// Algorithms needing more per-card state than `Card` has can keep it in
+/// `Card::scheduler_state`.
 pub trait Sched {
+    /// Seconds until the card would be due after answering with `choice`.
     fn next_interval(&self, card: &Card, choice: Choice) -> i64;
-    fn next_interval_string(&self, card: &Card, choice: Choice) -> String;
 
-    fn answer_card(&self, card: &mut Card, choice: Choice);
+    fn next_interval_string(&self, card: &Card, choice: Choice) -> String {
+        answer_button_time(self.next_interval(card, choice) as f32)
+    }
+
+    /// `time_taken` is the milliseconds spent on the card, for the log.
+    fn answer_card(&self, card: &mut Card, choice: Choice, time_taken: i32) -> ReviewLog;
+
+    /// Like `answer_card`, also returning a token that restores the card to
+    /// how it was before the answer.
+    fn answer_card_with_undo(
+        &self,
+        card: &mut Card,
+        choice: Choice,
+        time_taken: i32,
+    ) -> (ReviewLog, UndoToken) {
+        let token = UndoToken::new(card);
+        let log = self.answer_card(card, choice, time_taken);
+        (log, token)
+    }
+
+    /// Hide the card until the next day.
+    fn bury_card(&self, card: &mut Card) {
+        if card.card_queue != CardQueue::Buried {
+            card.original_queue = Some(card.card_queue);
+            card.card_queue = CardQueue::Buried;
+            card.buried_day = Some(self.day_today());
+        }
+    }
+
+    /// Put a buried card back in the queue it was buried from, keeping its
+    /// schedule.
+    fn unbury_card(&self, card: &mut Card) {
+        if card.card_queue == CardQueue::Buried {
+            card.card_queue = card
+                .original_queue
+                .take()
+                .unwrap_or_else(|| card.queue_for_type());
+            card.buried_day = None;
+        }
+    }
 
-    fn bury_card(&self, card: &mut Card);
-    fn unbury_card(&self, card: &mut Card);
+    fn suspend_card(&self, card: &mut Card) {
+        card.card_queue = CardQueue::Suspended;
+    }
 
-    fn suspend_card(&self, card: &mut Card);
-    fn unsuspend_card(&self, card: &mut Card);
+    fn unsuspend_card(&self, card: &mut Card) {
+        card.card_queue = card.queue_for_type();
+    }
 
     fn schedule_card_as_new(&self, card: &mut Card);
     fn schedule_card_as_review(&self, card: &mut Card, min_days: i32, max_days: i32);
+
+    /// Seconds since the epoch, from the scheduler's clock.
+    fn now(&self) -> i64;
+    // read from the clock on every call, so a long-lived scheduler moves on
+    // to the next day at the rollover
+    fn day_cut_off(&self) -> i64;
+    fn day_today(&self) -> i64;
 }
 
 pub struct Scheduler {
     pub config: Config,
-    pub day_cut_off: i64,
-    pub day_today: i64,
+    clock: Box<dyn Clock>,
 }
 
 impl Scheduler {
-    pub fn new(config: Config, day_cut_off: i64) -> Self {
-        Self {
-            config,
-            day_cut_off,
-            day_today: day_cut_off / 86_400,
+    pub fn new(config: Config, clock: Box<dyn Clock>) -> Result<Self, Error> {
+        Ok(Self {
+            config: config.validated()?,
+            clock,
+        })
+    }
+
+    /// Random choices for a card are seeded from its id and reps, so the same
+    /// answer on two devices lands on the same due date. Cards without an id
+    /// would all share one seed, so they get a random one.
+    fn rng(card: &Card) -> ChaCha8Rng {
+        if card.id == 0 {
+            return ChaCha8Rng::from_entropy();
         }
+        ChaCha8Rng::seed_from_u64((card.id as u64).wrapping_add(card.reps as u64))
     }
 
-    fn fuzz_interval(interval: i32) -> i32 {
+    pub(crate) fn random_days(card: &Card, min_days: i32, max_days: i32) -> i32 {
+        let distribution = Uniform::from(min_days..=max_days);
+        distribution.sample(&mut Self::rng(card))
+    }
+
+    pub(crate) fn fuzz_interval(card: &Card, interval: i32) -> i32 {
         let (min, max) = Self::fuzz_interval_range(interval);
-        let mut rng = rand::thread_rng();
-        rng.gen_range(min..=max)
+        Self::rng(card).gen_range(min..=max)
     }
 
-    fn fuzz_interval_range(interval: i32) -> (i32, i32) {
+    pub(crate) fn fuzz_interval_range(interval: i32) -> (i32, i32) {
         match interval {
             0..=1 => (1, 1),
             2 => (2, 3),
@@ -89,6 +157,8 @@ impl Sched for Scheduler {
                     } else {
                         self.lapse_interval(card) as i64 * 86_400
                     }
+                } else if self.is_early(card) {
+                    self.early_review_interval(card, choice) as i64 * 86_400
                 } else {
                     self.next_review_interval(card, choice, false) as i64 * 86_400
                 }
@@ -96,61 +166,42 @@ impl Sched for Scheduler {
         }
     }
 
-    fn next_interval_string(&self, card: &Card, choice: Choice) -> String {
-        let interval_secs = self.next_interval(card, choice);
-        answer_button_time(interval_secs as f32)
-    }
-
-    fn answer_card(&self, card: &mut Card, choice: Choice) {
+    fn answer_card(&self, card: &mut Card, choice: Choice, time_taken: i32) -> ReviewLog {
+        let before = card.clone();
         self.answer(card, choice);
+        ReviewLog::new(&before, card, choice, self.now(), time_taken)
     }
 
-    fn bury_card(&self, card: &mut Card) {
-        card.card_queue = CardQueue::Buried;
+    fn schedule_card_as_new(&self, card: &mut Card) {
+        card.schedule_as_new(0, self.config.initial_ease);
     }
 
-    fn unbury_card(&self, card: &mut Card) {
-        self.schedule_card_as_new(card);
+    fn schedule_card_as_review(&self, card: &mut Card, min_days: i32, max_days: i32) {
+        let interval = Self::random_days(card, min_days, max_days);
+        card.schedule_as_review(interval, self.day_today(), self.config.initial_ease);
     }
 
-    fn suspend_card(&self, card: &mut Card) {
-        card.card_queue = CardQueue::Suspended;
+    fn now(&self) -> i64 {
+        self.clock.now()
     }
 
-    fn unsuspend_card(&self, card: &mut Card) {
-        card.card_queue = match card.card_type {
-            CardType::Learn | CardType::Relearn => {
-                if card.due > 1_000_000_000 {
-                    CardQueue::Learn
-                } else {
-                    CardQueue::DayLearn
-                }
-            }
-            CardType::New => CardQueue::New,
-            CardType::Review => CardQueue::Review,
-        }
+    fn day_cut_off(&self) -> i64 {
+        self.config.day_cut_off(self.clock.as_ref())
     }
 
-    fn schedule_card_as_new(&self, card: &mut Card) {
-        card.schedule_as_new(0, self.config.initial_ease);
-    }
-
-    fn schedule_card_as_review(&self, card: &mut Card, min_days: i32, max_days: i32) {
-        let mut rng = rand::thread_rng();
-        let distribution = Uniform::from(min_days..=max_days);
-        let interval = distribution.sample(&mut rng);
-        card.schedule_as_review(interval, self.day_today, self.config.initial_ease);
+    fn day_today(&self) -> i64 {
+        self.config.day_today(self.clock.as_ref())
     }
 }
 
 impl Scheduler {
-    fn answer(&self, card: &mut Card, choice: Choice) {
+    pub(crate) fn answer(&self, card: &mut Card, choice: Choice) {
         card.reps += 1;
 
         if matches!(card.card_queue, CardQueue::New) {
             card.card_queue = CardQueue::Learn;
             card.card_type = CardType::Learn;
-            card.remaining_steps = self.start_remaining_steps(card);
+            card.learning_state = Some(self.start_learning_state(StepKind::Learn));
         }
 
         match card.card_queue {
@@ -164,20 +215,36 @@ impl Scheduler {
         }
     }
 
-    fn start_remaining_steps(&self, card: &Card) -> i32 {
-        let steps = match card.card_type {
-            CardType::Relearn => &self.config.relearn_steps,
-            _ => &self.config.learn_steps,
-        };
+    fn steps(&self, kind: StepKind) -> &[f32] {
+        match kind {
+            StepKind::Learn => &self.config.learn_steps,
+            StepKind::Relearn => &self.config.relearn_steps,
+        }
+    }
 
-        let total_steps = steps.len();
-        let total_remaining = self.remaining_today(steps, total_steps);
-        total_steps as i32 + total_remaining * 1_000
+    fn start_learning_state(&self, kind: StepKind) -> LearningState {
+        let steps = self.steps(kind);
+        LearningState {
+            kind,
+            remaining: steps.len() as u32,
+            remaining_today: self.remaining_today(steps, steps.len()),
+        }
+    }
+
+    /// Learning cards without a state, eg added straight to the learn queue,
+    /// start from their first step.
+    fn learning_state(&self, card: &Card) -> LearningState {
+        card.learning_state.unwrap_or_else(|| {
+            self.start_learning_state(match card.card_type {
+                CardType::Relearn => StepKind::Relearn,
+                _ => StepKind::Learn,
+            })
+        })
     }
 
     // The number of steps that can be completed by the day cutoff
-    fn remaining_today(&self, steps: &[f32], remaining: usize) -> i32 {
-        let mut now = Timestamp::now() as f32;
+    fn remaining_today(&self, steps: &[f32], remaining: usize) -> u32 {
+        let mut now = self.now() as f32;
         let from_idx = if steps.len() > remaining {
             steps.len() - remaining
         } else {
@@ -185,7 +252,7 @@ impl Scheduler {
         };
         let remaining_steps = &steps[from_idx..steps.len()];
         let mut remain = 0;
-        let day_cut_off = self.day_cut_off as f32;
+        let day_cut_off = self.day_cut_off() as f32;
         for (i, item) in remaining_steps.iter().enumerate() {
             now += item * 60.0;
             if now > day_cut_off {
@@ -193,26 +260,28 @@ impl Scheduler {
             }
             remain = i
         }
-        (remain + 1) as i32
+        (remain + 1) as u32
     }
 
     fn answer_learn_card(&self, card: &mut Card, choice: Choice) {
-        let steps = &self.config.learn_steps.clone();
+        let state = self.learning_state(card);
+        card.learning_state = Some(state);
         match choice {
             Choice::Easy => self.reschedule_as_review(card, true),
             Choice::Ok => {
-                if card.remaining_steps % 1_000 <= 1 {
+                if state.remaining <= 1 {
                     self.reschedule_as_review(card, false)
                 } else {
-                    self.move_to_next_step(card, steps)
+                    self.move_to_next_step(card, state)
                 }
             }
-            Choice::Hard => self.repeat_step(card, steps),
-            Choice::Again => self.move_to_first_step(card, steps),
+            Choice::Hard => self.repeat_step(card, state),
+            Choice::Again => self.move_to_first_step(card, state.kind),
         }
     }
 
     fn reschedule_as_review(&self, card: &mut Card, early: bool) {
+        card.learning_state = None;
         match card.card_type {
             CardType::Review | CardType::Relearn => self.reschedule_graduating_lapse(card, early),
             _ => self.reschedule_new(card, early),
@@ -223,14 +292,14 @@ impl Scheduler {
         if early {
             card.interval += 1
         }
-        card.due = self.day_today + card.interval as i64;
+        card.due = self.day_today() + card.interval as i64;
         card.card_type = CardType::Review;
         card.card_queue = CardQueue::Review;
     }
 
     fn reschedule_new(&self, card: &mut Card, early: bool) {
         card.interval = self.graduating_interval(card, early, true);
-        card.due = self.day_today + card.interval as i64;
+        card.due = self.day_today() + card.interval as i64;
         card.ease_factor = self.config.initial_ease;
         card.card_queue = CardQueue::Review;
         card.card_type = CardType::Review;
@@ -250,7 +319,7 @@ impl Scheduler {
                 };
 
                 if fuzzy {
-                    Self::fuzz_interval(ideal)
+                    Self::fuzz_interval(card, ideal)
                 } else {
                     ideal
                 }
@@ -258,8 +327,14 @@ impl Scheduler {
         }
     }
 
+    /// Reviews before the due day are scheduled from the days that actually
+    /// passed since the last review.
+    fn is_early(&self, card: &Card) -> bool {
+        card.card_queue == CardQueue::Review && card.due > self.day_today()
+    }
+
     fn answer_review_card(&self, card: &mut Card, choice: Choice) {
-        let early = false;
+        let early = self.is_early(card);
         match choice {
             Choice::Again => self.reschedule_lapse(card),
             _ => self.reschedule_review(card, choice, early),
@@ -274,27 +349,28 @@ impl Scheduler {
         }
 
         card.ease_factor = max(
-            1_300,
+            MINIMUM_EASE,
             card.ease_factor + vec![-150, 0, 150][choice as usize - 2],
         );
-        card.due = self.day_today + card.interval as i64;
+        card.due = self.day_today() + card.interval as i64;
     }
 
     fn update_early_review_interval(&self, card: &mut Card, choice: Choice) {
         card.interval = self.early_review_interval(card, choice)
     }
 
-    fn early_review_interval(&self, card: &mut Card, choice: Choice) -> i32 {
-        let elapsed = self.day_today + card.interval as i64;
+    fn early_review_interval(&self, card: &Card, choice: Choice) -> i32 {
+        let elapsed = card.interval as i64 - (card.due - self.day_today());
 
         let mut easy_bonus = 1.0;
-        let mut min_new_interval = 1;
+        // early reviews shouldn't shrink the interval, or halve it for hard
+        let mut min_new_interval = 1.0;
         let factor: f32;
 
         match choice {
             Choice::Hard => {
                 factor = self.config.hard_multiplier;
-                min_new_interval = (factor / 2.0) as i32;
+                min_new_interval = factor / 2.0;
             }
             Choice::Ok => {
                 factor = card.ease_factor as f32 / 1_000.0;
@@ -307,16 +383,16 @@ impl Scheduler {
         }
 
         let mut interval = f32::max(elapsed as f32 * factor, 1.0);
-        interval = f32::max((card.interval * min_new_interval) as f32, interval) * easy_bonus;
-        self.constrain_interval(interval, 0, false)
+        interval = f32::max(card.interval as f32 * min_new_interval, interval) * easy_bonus;
+        self.constrain_interval(card, interval, 0, false)
     }
 
-    fn constrain_interval(&self, interval: f32, previous: i32, fuzzy: bool) -> i32 {
+    fn constrain_interval(&self, card: &Card, interval: f32, previous: i32, fuzzy: bool) -> i32 {
         let mut interval = (interval * self.config.interval_multiplier) as i32;
         if fuzzy {
-            interval = Self::fuzz_interval(interval);
+            interval = Self::fuzz_interval(card, interval);
         }
-        interval = max(max(interval as i32, previous + 1), 1);
+        interval = max(max(interval, previous + 1), 1);
         min(interval, self.config.maximum_review_interval)
     }
 
@@ -328,14 +404,15 @@ impl Scheduler {
         let factor = card.ease_factor as f32 / 1_000.0;
         let delay = self.days_late(card);
         let hard_factor = self.config.hard_multiplier;
-        let hard_min = if hard_factor > 1.0 { card.interval } else { 0 } as i32;
+        let hard_min = if hard_factor > 1.0 { card.interval } else { 0 };
         let mut interval =
-            self.constrain_interval(card.interval as f32 * hard_factor, hard_min, fuzzy);
+            self.constrain_interval(card, card.interval as f32 * hard_factor, hard_min, fuzzy);
         if matches!(choice, Choice::Hard) {
             return interval;
         }
 
         interval = self.constrain_interval(
+            card,
             (card.interval as f32 + delay as f32 / 2.0) * factor,
             interval,
             fuzzy,
@@ -345,6 +422,7 @@ impl Scheduler {
         }
 
         self.constrain_interval(
+            card,
             ((card.interval + delay) as f32 * factor) * self.config.easy_multiplier,
             interval,
             fuzzy,
@@ -353,7 +431,7 @@ impl Scheduler {
 
     fn reschedule_lapse(&self, card: &mut Card) {
         card.lapses += 1;
-        card.ease_factor = max(1_300, card.ease_factor - 200);
+        card.ease_factor = max(MINIMUM_EASE, card.ease_factor - 200);
 
         let leech = self.check_leech(card);
         // Always suspend card on leech
@@ -362,10 +440,9 @@ impl Scheduler {
         }
         let suspended = matches!(card.card_queue, CardQueue::Suspended);
 
-        let steps = &self.config.relearn_steps.clone();
-        if !steps.is_empty() && !suspended {
+        if !self.config.relearn_steps.is_empty() && !suspended {
             card.card_type = CardType::Relearn;
-            self.move_to_first_step(card, steps);
+            self.move_to_first_step(card, StepKind::Relearn);
         } else {
             self.update_review_interval_on_fail(card);
             self.reschedule_as_review(card, false);
@@ -400,54 +477,54 @@ impl Scheduler {
     }
 
     fn days_late(&self, card: &Card) -> i32 {
-        max(0, self.day_today - card.due) as i32
+        max(0, self.day_today() - card.due) as i32
     }
 
-    fn move_to_next_step(&self, card: &mut Card, steps: &[f32]) {
-        let remaining = (card.remaining_steps % 1_000) - 1;
-        card.remaining_steps = self.remaining_today(steps, remaining as usize) * 1_000 + remaining;
+    fn move_to_next_step(&self, card: &mut Card, state: LearningState) {
+        let steps = self.steps(state.kind);
+        let remaining = state.remaining - 1;
+        let state = LearningState {
+            remaining,
+            remaining_today: self.remaining_today(steps, remaining as usize),
+            ..state
+        };
+        card.learning_state = Some(state);
 
-        self.reschedule_learn_card(card, steps, None);
+        self.reschedule_learn_card(card, self.delay_for_grade(steps, remaining));
     }
 
-    fn repeat_step(&self, card: &mut Card, steps: &[f32]) {
-        let delay = self.delay_for_repeating_grade(steps, card.remaining_steps);
-        self.reschedule_learn_card(card, steps, Some(delay))
+    fn repeat_step(&self, card: &mut Card, state: LearningState) {
+        let delay = self.delay_for_repeating_grade(self.steps(state.kind), state.remaining);
+        self.reschedule_learn_card(card, delay)
     }
 
-    fn reschedule_learn_card(&self, card: &mut Card, steps: &[f32], delay: Option<i32>) {
-        let delay = match delay {
-            None => self.delay_for_grade(steps, card.remaining_steps),
-            Some(value) => value,
-        };
-
-        card.due = Timestamp::now() + delay as i64;
+    fn reschedule_learn_card(&self, card: &mut Card, delay: i32) {
+        card.due = self.now() + delay as i64;
 
-        if card.due < self.day_cut_off {
+        if card.due < self.day_cut_off() {
             let max_extra = min(300, (delay as f32 * 0.25) as i64);
-            let mut rng = rand::thread_rng();
-            let fuzz = rng.gen_range(0..=max(1, max_extra));
-            card.due = min(self.day_cut_off - 1, card.due + fuzz);
+            let fuzz = Self::rng(card).gen_range(0..=max(1, max_extra));
+            card.due = min(self.day_cut_off() - 1, card.due + fuzz);
             card.card_queue = CardQueue::Learn;
         } else {
-            let ahead = ((card.due - self.day_cut_off) / 86_400) + 1;
-            card.due = self.day_today + ahead;
+            let ahead = ((card.due - self.day_cut_off()) / 86_400) + 1;
+            card.due = self.day_today() + ahead;
             card.card_queue = CardQueue::DayLearn;
         }
     }
 
-    fn delay_for_repeating_grade(&self, steps: &[f32], remaining: i32) -> i32 {
+    fn delay_for_repeating_grade(&self, steps: &[f32], remaining: u32) -> i32 {
         let delay1 = self.delay_for_grade(steps, remaining);
         let delay2 = if steps.len() > 1 {
-            self.delay_for_grade(steps, remaining - 1)
+            self.delay_for_grade(steps, remaining.saturating_sub(1))
         } else {
             delay1 * 2
         };
         (delay1 + max(delay1, delay2)) / 2
     }
 
-    fn delay_for_grade(&self, steps: &[f32], remaining: i32) -> i32 {
-        let left = (remaining % 1_000) as usize;
+    fn delay_for_grade(&self, steps: &[f32], remaining: u32) -> i32 {
+        let left = remaining as usize;
         let delay = if steps.is_empty() {
             1.0
         } else if steps.len() >= left && left > 0 {
@@ -458,33 +535,34 @@ impl Scheduler {
         (delay * 60.0) as i32
     }
 
-    fn move_to_first_step(&self, card: &mut Card, steps: &[f32]) {
-        card.remaining_steps = self.start_remaining_steps(card);
+    fn move_to_first_step(&self, card: &mut Card, kind: StepKind) {
+        let state = self.start_learning_state(kind);
+        card.learning_state = Some(state);
         if matches!(card.card_type, CardType::Relearn) {
             self.update_review_interval_on_fail(card)
         }
-        self.reschedule_learn_card(card, steps, None)
+        self.reschedule_learn_card(
+            card,
+            self.delay_for_grade(self.steps(kind), state.remaining),
+        )
     }
 
     fn next_learn_interval(&self, card: &Card, choice: Choice) -> i32 {
-        let steps = &self.config.learn_steps;
+        let state = if matches!(card.card_queue, CardQueue::New) {
+            self.start_learning_state(StepKind::Learn)
+        } else {
+            self.learning_state(card)
+        };
+        let steps = self.steps(state.kind);
         match choice {
-            Choice::Again => self.delay_for_grade(steps, steps.len() as i32),
-            Choice::Hard => self.delay_for_repeating_grade(steps, steps.len() as i32),
+            Choice::Again => self.delay_for_grade(steps, steps.len() as u32),
+            Choice::Hard => self.delay_for_repeating_grade(steps, steps.len() as u32),
             Choice::Easy => self.graduating_interval(card, true, false) * 86_400,
             Choice::Ok => {
-                let remaining = if matches!(card.card_queue, CardQueue::New) {
-                    self.start_remaining_steps(card)
-                } else {
-                    card.remaining_steps
-                };
-
-                let left = remaining % 1_000 - 1;
-
-                if left <= 0 {
+                if state.remaining <= 1 {
                     self.graduating_interval(card, false, false) * 86_400
                 } else {
-                    self.delay_for_grade(steps, left)
+                    self.delay_for_grade(steps, state.remaining - 1)
                 }
             }
         }
@@ -494,7 +572,9 @@ impl Scheduler {
 #[cfg(test)]
 mod tests {
     use crate::srs::card::CardType;
-    use crate::svc::timestamp::Timestamp;
+    use crate::srs::review_log::ReviewKind;
+    use crate::srs::test_util::{scheduler, NOW};
+    use crate::svc::clock::FixedClock;
 
     use super::*;
 
@@ -503,56 +583,69 @@ mod tests {
         card.interval >= min && card.interval <= max
     }
 
+    // steps left, and how many of them fit in today
+    fn learning(card: &Card) -> (u32, u32) {
+        let state = card.learning_state.unwrap();
+        (state.remaining, state.remaining_today)
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = Config {
+            hard_multiplier: 0.0,
+            ..Config::default()
+        };
+        let result = Scheduler::new(config, Box::new(FixedClock::new(NOW, 0)));
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    }
+
     #[test]
     fn test_new() {
-        let scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
+        let scheduler = scheduler();
         let mut card = Card::default();
-        scheduler.answer_card(&mut card, Choice::Again);
+        scheduler.answer_card(&mut card, Choice::Again, 0);
         assert!(matches!(card.card_queue, CardQueue::Learn));
         assert!(matches!(card.card_type, CardType::Learn));
-        assert!(card.due >= Timestamp::now());
+        assert!(card.due >= NOW);
     }
 
     #[test]
     fn test_change_steps() {
-        let mut scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
+        let mut scheduler = scheduler();
         let mut card = Card::default();
         scheduler.config.learn_steps = vec![1.0, 2.0, 3.0, 4.0, 5.0];
-        scheduler.answer_card(&mut card, Choice::Ok);
+        scheduler.answer_card(&mut card, Choice::Ok, 0);
         scheduler.config.learn_steps = vec![1.0];
-        scheduler.answer_card(&mut card, Choice::Ok);
+        scheduler.answer_card(&mut card, Choice::Ok, 0);
     }
 
     #[test]
     fn test_learn() {
-        let mut scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
+        let mut scheduler = scheduler();
         let mut card = Card::default();
 
         // Fail it
         scheduler.config.learn_steps = vec![0.5, 3.0, 10.0];
         scheduler.answer(&mut card, Choice::Again);
         // Got 3 steps before graduation
-        assert_eq!(card.remaining_steps % 1_000, 3);
-        assert_eq!(card.remaining_steps / 1_000, 3);
+        assert_eq!(learning(&card), (3, 3));
         // Due in 30 seconds
-        let t1 = card.due - Timestamp::now();
-        assert!(t1 >= 25 && t1 <= 40);
+        let t1 = card.due - NOW;
+        assert!((25..=40).contains(&t1));
 
         // Pass it once
         scheduler.answer(&mut card, Choice::Ok);
         // Due in 3 minutes
-        let t2 = card.due - Timestamp::now();
-        assert!(t2 >= 178 && t2 <= 225);
-        assert_eq!(card.remaining_steps % 1_000, 2);
-        assert_eq!(card.remaining_steps / 1_000, 2);
+        let t2 = card.due - NOW;
+        assert!((178..=225).contains(&t2));
+        assert_eq!(learning(&card), (2, 2));
 
         // Pass again
         scheduler.answer(&mut card, Choice::Ok);
         // Due in 10 minutes
-        let t3 = card.due - Timestamp::now();
-        assert!(t3 >= 599 && t3 <= 750);
-        assert_eq!(card.remaining_steps % 1_000, 1);
-        assert_eq!(card.remaining_steps / 1_000, 1);
+        let t3 = card.due - NOW;
+        assert!((599..=750).contains(&t3));
+        assert_eq!(learning(&card), (1, 1));
 
         // Graduate the card
         assert!(matches!(card.card_type, CardType::Learn));
@@ -561,7 +654,7 @@ mod tests {
         assert!(matches!(card.card_type, CardType::Review));
         assert!(matches!(card.card_queue, CardQueue::Review));
         // Due tomorrow with interval of 1
-        assert_eq!(card.due, scheduler.day_today + 1);
+        assert_eq!(card.due, scheduler.day_today() + 1);
         assert_eq!(card.interval, 1);
         // Or normal removal
         card.card_type = CardType::New;
@@ -572,49 +665,77 @@ mod tests {
         assert!(check_interval(&card, 4));
     }
 
+    #[test]
+    fn test_learn_near_day_cut_off() {
+        // five minutes before the 4am rollover
+        let clock = FixedClock::new(NOW + 16 * 3_600 - 300, 0);
+        let scheduler = Scheduler::new(Config::default(), Box::new(clock)).unwrap();
+        assert_eq!(scheduler.day_cut_off(), NOW + 16 * 3_600);
+        let mut card = Card::default();
+
+        // A 10 minute step would cross the rollover
+        scheduler.answer(&mut card, Choice::Ok);
+        assert!(matches!(card.card_queue, CardQueue::DayLearn));
+        assert_eq!(card.due, scheduler.day_today() + 1);
+    }
+
     #[test]
     fn test_initial_hard() {
-        let scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
+        let scheduler = scheduler();
         let mut card = Card::default();
 
         scheduler.answer(&mut card, Choice::Hard);
-        let expected = Timestamp::now() + 330;
+        let expected = NOW + 330;
         assert!(card.due >= expected - 10 && card.due <= (expected as f32 * 1.25) as i64);
     }
 
     #[test]
     fn test_relearn() {
-        let scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
-        let mut card = Card::default();
-
-        card.interval = 100;
-        card.due = scheduler.day_today;
-        card.card_queue = CardQueue::Review;
-        card.card_type = CardType::Review;
+        let scheduler = scheduler();
+        let mut card = Card {
+            interval: 100,
+            due: scheduler.day_today(),
+            card_queue: CardQueue::Review,
+            card_type: CardType::Review,
+            ..Card::default()
+        };
 
         // Fail the card
         scheduler.answer(&mut card, Choice::Again);
         assert!(matches!(card.card_type, CardType::Relearn));
         assert!(matches!(card.card_queue, CardQueue::Learn));
         assert_eq!(card.interval, 1);
+        assert_eq!(card.learning_state.unwrap().kind, StepKind::Relearn);
+
+        // Relearning goes through the relearn steps
+        let mut scheduler = scheduler;
+        scheduler.config.relearn_steps = vec![5.0, 10.0];
+        scheduler.answer(&mut card, Choice::Again);
+        assert_eq!(learning(&card), (2, 2));
+        assert!((300..=375).contains(&(card.due - NOW)));
+        scheduler.answer(&mut card, Choice::Ok);
+        assert_eq!(learning(&card), (1, 1));
+        assert!((600..=750).contains(&(card.due - NOW)));
 
         // Immediately graduate it
         scheduler.answer(&mut card, Choice::Easy);
+        assert_eq!(card.learning_state, None);
         assert!(matches!(card.card_type, CardType::Review));
         assert!(matches!(card.card_queue, CardQueue::Review));
         assert_eq!(card.interval, 2);
-        assert_eq!(card.due, scheduler.day_today + card.interval as i64);
+        assert_eq!(card.due, scheduler.day_today() + card.interval as i64);
     }
 
     #[test]
     fn test_relearn_no_steps() {
-        let mut scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
-        let mut card = Card::default();
-
-        card.interval = 100;
-        card.due = scheduler.day_today;
-        card.card_queue = CardQueue::Review;
-        card.card_type = CardType::Review;
+        let mut scheduler = scheduler();
+        let mut card = Card {
+            interval: 100,
+            due: scheduler.day_today(),
+            card_queue: CardQueue::Review,
+            card_type: CardType::Review,
+            ..Card::default()
+        };
 
         scheduler.config.relearn_steps = vec![];
         // Fail the card
@@ -625,25 +746,24 @@ mod tests {
 
     #[test]
     fn test_learn_day() {
-        let mut scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
+        let mut scheduler = scheduler();
         let mut card = Card::default();
 
         scheduler.config.learn_steps = vec![1.0, 10.0, 1440.0, 2880.0];
 
         // Pass it
         scheduler.answer(&mut card, Choice::Ok);
-        assert_eq!(card.remaining_steps % 1_000, 3);
-        assert_eq!(card.remaining_steps / 1_000, 1);
-        assert_eq!(scheduler.next_interval(&mut card, Choice::Ok), 86_400);
+        assert_eq!(learning(&card), (3, 1));
+        assert_eq!(scheduler.next_interval(&card, Choice::Ok), 86_400);
 
         // Learn it
         scheduler.answer(&mut card, Choice::Ok);
-        assert_eq!(card.due, scheduler.day_today + 1);
+        assert_eq!(card.due, scheduler.day_today() + 1);
         assert!(matches!(card.card_queue, CardQueue::DayLearn));
 
         // Move back a day
         card.due -= 1;
-        assert_eq!(scheduler.next_interval(&mut card, Choice::Ok), 86_400 * 2);
+        assert_eq!(scheduler.next_interval(&card, Choice::Ok), 86_400 * 2);
 
         // Fail to answer it
         scheduler.answer(&mut card, Choice::Again);
@@ -651,22 +771,23 @@ mod tests {
 
         // Ok to answer it
         scheduler.answer(&mut card, Choice::Ok);
-        assert_eq!(scheduler.next_interval(&mut card, Choice::Ok), 86_400);
+        assert_eq!(scheduler.next_interval(&card, Choice::Ok), 86_400);
         assert!(matches!(card.card_queue, CardQueue::Learn));
     }
 
     #[test]
     fn test_review() {
-        let scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
-        let mut card = Card::default();
-
-        card.card_type = CardType::Review;
-        card.card_queue = CardQueue::Review;
-        card.due = scheduler.day_today - 8;
-        card.ease_factor = 2_500;
-        card.reps = 3;
-        card.lapses = 1;
-        card.interval = 100;
+        let scheduler = scheduler();
+        let mut card = Card {
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: scheduler.day_today() - 8,
+            ease_factor: 2_500,
+            reps: 3,
+            lapses: 1,
+            interval: 100,
+            ..Card::default()
+        };
 
         let card_copy = card.clone();
 
@@ -674,7 +795,7 @@ mod tests {
         scheduler.answer(&mut card, Choice::Hard);
         assert!(matches!(card.card_queue, CardQueue::Review));
         assert!(check_interval(&card, 120));
-        assert_eq!(card.due, scheduler.day_today + card.interval as i64);
+        assert_eq!(card.due, scheduler.day_today() + card.interval as i64);
         assert_eq!(card.ease_factor, 2_350);
         assert_eq!(card.lapses, 1);
         assert_eq!(card.reps, 4);
@@ -684,7 +805,7 @@ mod tests {
         scheduler.answer(&mut card, Choice::Ok);
         assert!(matches!(card.card_queue, CardQueue::Review));
         assert!(check_interval(&card, 260));
-        assert_eq!(card.due, scheduler.day_today + card.interval as i64);
+        assert_eq!(card.due, scheduler.day_today() + card.interval as i64);
         assert_eq!(card.ease_factor, 2_500);
 
         // Easy
@@ -692,7 +813,7 @@ mod tests {
         scheduler.answer(&mut card, Choice::Easy);
         assert!(matches!(card.card_queue, CardQueue::Review));
         assert!(check_interval(&card, 351));
-        assert_eq!(card.due, scheduler.day_today + card.interval as i64);
+        assert_eq!(card.due, scheduler.day_today() + card.interval as i64);
         assert_eq!(card.ease_factor, 2_650);
 
         // Leech
@@ -703,37 +824,218 @@ mod tests {
     }
 
     #[test]
-    fn test_spacing_button() {
-        let mut scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
-        let mut card = Card::default();
+    fn test_fuzz_is_deterministic() {
+        let review = Card {
+            id: 1_614_000_000_123,
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: NOW / 86_400,
+            ease_factor: 2_500,
+            reps: 5,
+            interval: 100,
+            ..Card::default()
+        };
 
-        card.card_type = CardType::Review;
-        card.card_queue = CardQueue::Review;
-        card.due = scheduler.day_today;
-        card.reps = 1;
-        card.interval = 1;
+        // Same answer on two devices
+        let mut card1 = review.clone();
+        let mut card2 = review.clone();
+        scheduler().answer(&mut card1, Choice::Ok);
+        scheduler().answer(&mut card2, Choice::Ok);
+        assert_eq!(card1.interval, card2.interval);
+        assert_eq!(card1.due, card2.due);
+
+        // Learning steps are jittered the same way too
+        let new = Card {
+            id: 7,
+            ..Card::new(1)
+        };
+        let mut card1 = new.clone();
+        let mut card2 = new.clone();
+        scheduler().answer(&mut card1, Choice::Again);
+        scheduler().answer(&mut card2, Choice::Again);
+        assert_eq!(card1.due, card2.due);
+
+        // But different cards are spread out
+        let intervals: Vec<i32> = (0..10)
+            .map(|id| {
+                let mut card = Card {
+                    id,
+                    ..review.clone()
+                };
+                scheduler().answer(&mut card, Choice::Ok);
+                card.interval
+            })
+            .collect();
+        assert!(intervals.iter().any(|&i| i != intervals[0]));
+    }
 
-        assert_eq!(
-            scheduler.next_interval_string(&mut card, Choice::Hard),
-            "2d"
-        );
-        assert_eq!(scheduler.next_interval_string(&mut card, Choice::Ok), "3d");
-        assert_eq!(
-            scheduler.next_interval_string(&mut card, Choice::Easy),
-            "4d"
-        );
+    #[test]
+    fn test_fuzz_without_id() {
+        // cards without an id aren't all fuzzed alike
+        let review = Card {
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: NOW / 86_400,
+            ease_factor: 2_500,
+            reps: 5,
+            interval: 100,
+            ..Card::default()
+        };
+        let intervals: Vec<i32> = (0..20)
+            .map(|_| {
+                let mut card = review.clone();
+                scheduler().answer(&mut card, Choice::Ok);
+                card.interval
+            })
+            .collect();
+        assert!(intervals.iter().any(|&i| i != intervals[0]));
+    }
+
+    #[test]
+    fn test_early_review() {
+        let scheduler = scheduler();
+        // 10 day interval, reviewed after 5 days
+        let mut card = Card {
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: scheduler.day_today() + 5,
+            ease_factor: 2_500,
+            reps: 3,
+            interval: 10,
+            ..Card::default()
+        };
+        let card_copy = card.clone();
+
+        // Hard: 5 * 1.2, but no less than half of 10 * 1.2
+        assert_eq!(scheduler.next_interval(&card, Choice::Hard), 6 * 86_400);
+        scheduler.answer(&mut card, Choice::Hard);
+        assert_eq!(card.interval, 6);
+        assert_eq!(card.due, scheduler.day_today() + 6);
+        assert_eq!(card.ease_factor, 2_350);
+
+        // Ok: 5 * 2.5, not fuzzed
+        card = card_copy.clone();
+        assert_eq!(scheduler.next_interval(&card, Choice::Ok), 12 * 86_400);
+        scheduler.answer(&mut card, Choice::Ok);
+        assert_eq!(card.interval, 12);
+        assert_eq!(card.due, scheduler.day_today() + 12);
+        assert_eq!(card.ease_factor, 2_500);
+
+        // Easy: 5 * 2.5 with half the easy bonus
+        card = card_copy.clone();
+        assert_eq!(scheduler.next_interval(&card, Choice::Easy), 14 * 86_400);
+        scheduler.answer(&mut card, Choice::Easy);
+        assert_eq!(card.interval, 14);
+        assert_eq!(card.ease_factor, 2_650);
+
+        // Reviewed a day early, the interval doesn't shrink
+        card = Card {
+            due: scheduler.day_today() + 1,
+            ..card_copy.clone()
+        };
+        scheduler.answer(&mut card, Choice::Ok);
+        assert_eq!(card.interval, 22);
+
+        // On the due day it's a normal review
+        card = Card {
+            due: scheduler.day_today(),
+            ..card_copy
+        };
+        scheduler.answer(&mut card, Choice::Ok);
+        assert!(check_interval(&card, 25));
+    }
+
+    #[test]
+    fn test_review_log() {
+        let scheduler = scheduler();
+        let mut card = Card {
+            id: 42,
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: scheduler.day_today(),
+            ease_factor: 2_500,
+            interval: 10,
+            ..Card::default()
+        };
+
+        let log = scheduler.answer_card(&mut card, Choice::Hard, 4_500);
+        assert_eq!(log.card_id, 42);
+        assert_eq!(log.timestamp, NOW);
+        assert_eq!(log.choice, Choice::Hard);
+        assert_eq!(log.last_interval, 10);
+        assert_eq!(log.interval, card.interval);
+        assert_eq!(log.last_ease_factor, 2_500);
+        assert_eq!(log.ease_factor, 2_350);
+        assert_eq!(log.card_type, CardType::Review);
+        assert_eq!(log.time_taken, 4_500);
+        assert_eq!(log.review_kind, ReviewKind::Review);
+
+        let log = scheduler.answer_card(&mut card, Choice::Again, 0);
+        assert_eq!(log.review_kind, ReviewKind::Review);
+        let log = scheduler.answer_card(&mut card, Choice::Ok, 0);
+        assert_eq!(log.card_type, CardType::Relearn);
+        assert_eq!(log.review_kind, ReviewKind::Relearn);
+
+        let log = scheduler.answer_card(&mut Card::default(), Choice::Ok, 0);
+        assert_eq!(log.card_type, CardType::New);
+        assert_eq!(log.review_kind, ReviewKind::Learn);
+    }
+
+    #[test]
+    fn test_undo_leech() {
+        let scheduler = scheduler();
+        let mut card = Card {
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: scheduler.day_today(),
+            ease_factor: 2_500,
+            interval: 100,
+            reps: 20,
+            lapses: 7,
+            ..Card::default()
+        };
+        let original = card.clone();
+
+        // Mis-tapped Again turns it into a leech
+        let (_, token) = scheduler.answer_card_with_undo(&mut card, Choice::Again, 0);
+        assert!(matches!(card.card_queue, CardQueue::Suspended));
+        assert_eq!(card.lapses, 8);
+
+        let card = token.restore();
+        assert!(matches!(card.card_queue, CardQueue::Review));
+        assert!(matches!(card.card_type, CardType::Review));
+        assert_eq!(card.due, original.due);
+        assert_eq!(card.interval, original.interval);
+        assert_eq!(card.ease_factor, original.ease_factor);
+        assert_eq!(card.reps, original.reps);
+        assert_eq!(card.lapses, original.lapses);
+        assert_eq!(card.learning_state, original.learning_state);
+    }
+
+    #[test]
+    fn test_spacing_button() {
+        let mut scheduler = scheduler();
+        let card = Card {
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: scheduler.day_today(),
+            reps: 1,
+            interval: 1,
+            ..Card::default()
+        };
+
+        assert_eq!(scheduler.next_interval_string(&card, Choice::Hard), "2d");
+        assert_eq!(scheduler.next_interval_string(&card, Choice::Ok), "3d");
+        assert_eq!(scheduler.next_interval_string(&card, Choice::Easy), "4d");
 
         // Hard multiplier = 1, not increase day
         scheduler.config.hard_multiplier = 1.0;
-        assert_eq!(
-            scheduler.next_interval_string(&mut card, Choice::Hard),
-            "1d"
-        );
+        assert_eq!(scheduler.next_interval_string(&card, Choice::Hard), "1d");
     }
 
     #[test]
     fn test_bury() {
-        let scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
+        let scheduler = scheduler();
         let mut card = Card::default();
 
         scheduler.bury_card(&mut card);
@@ -741,20 +1043,56 @@ mod tests {
 
         scheduler.unbury_card(&mut card);
         assert!(matches!(card.card_queue, CardQueue::New));
+
+        // Reviews keep their schedule
+        let mut card = Card {
+            card_type: CardType::Review,
+            card_queue: CardQueue::Review,
+            due: scheduler.day_today() + 3,
+            interval: 10,
+            ease_factor: 2_500,
+            ..Card::default()
+        };
+        let reviewed = card.clone();
+        scheduler.bury_card(&mut card);
+        assert_eq!(card.original_queue, Some(CardQueue::Review));
+        scheduler.bury_card(&mut card);
+        scheduler.unbury_card(&mut card);
+        assert_eq!(card.card_queue, CardQueue::Review);
+        assert_eq!(card.original_queue, None);
+        assert_eq!(card.due, reviewed.due);
+        assert_eq!(card.interval, reviewed.interval);
+
+        // A suspended card stays suspended
+        scheduler.suspend_card(&mut card);
+        scheduler.bury_card(&mut card);
+        scheduler.unbury_card(&mut card);
+        assert_eq!(card.card_queue, CardQueue::Suspended);
+
+        // Cards buried before the original queue was kept
+        let mut card = Card {
+            card_type: CardType::Learn,
+            card_queue: CardQueue::Buried,
+            due: scheduler.day_today(),
+            ..Card::default()
+        };
+        scheduler.unbury_card(&mut card);
+        assert_eq!(card.card_queue, CardQueue::DayLearn);
     }
 
     #[test]
     fn test_suspend() {
-        let scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
-        let mut card = Card::default();
-
-        card.due = scheduler.day_today;
-        card.interval = 100;
-        card.card_queue = CardQueue::Review;
-        card.card_type = CardType::Review;
+        let scheduler = scheduler();
+        let mut card = Card {
+            due: scheduler.day_today(),
+            interval: 100,
+            card_queue: CardQueue::Review,
+            card_type: CardType::Review,
+            ..Card::default()
+        };
 
         scheduler.answer(&mut card, Choice::Again);
-        assert!(card.due > Timestamp::now());
+        assert!(card.due > NOW);
         assert!(matches!(card.card_type, CardType::Relearn));
         assert!(matches!(card.card_queue, CardQueue::Learn));
 
@@ -769,17 +1107,17 @@ mod tests {
 
     #[test]
     fn test_reschedule() {
-        let scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
+        let scheduler = scheduler();
         let mut card = Card::default();
 
         scheduler.schedule_card_as_review(&mut card, 0, 0);
-        assert_eq!(card.due, scheduler.day_today);
+        assert_eq!(card.due, scheduler.day_today());
         assert_eq!(card.interval, 1);
         assert!(matches!(card.card_queue, CardQueue::Review));
         assert!(matches!(card.card_type, CardType::Review));
 
         scheduler.schedule_card_as_review(&mut card, 1, 1);
-        assert_eq!(card.due, scheduler.day_today + 1);
+        assert_eq!(card.due, scheduler.day_today() + 1);
         assert_eq!(card.interval, 1);
 
         scheduler.schedule_card_as_new(&mut card);
@@ -790,16 +1128,17 @@ mod tests {
 
     #[test]
     fn test_fail_multiple() {
-        let mut scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
-        let mut card = Card::default();
-
-        card.interval = 100;
-        card.due = scheduler.day_today - 100;
-        card.card_queue = CardQueue::Review;
-        card.card_type = CardType::Review;
-        card.ease_factor = 2_500;
-        card.reps = 3;
-        card.lapses = 3;
+        let mut scheduler = scheduler();
+        let mut card = Card {
+            interval: 100,
+            due: scheduler.day_today() - 100,
+            card_queue: CardQueue::Review,
+            card_type: CardType::Review,
+            ease_factor: 2_500,
+            reps: 3,
+            lapses: 3,
+            ..Card::default()
+        };
 
         scheduler.config.lapse_multiplier = 0.5;
         scheduler.answer(&mut card, Choice::Again);
@@ -810,7 +1149,7 @@ mod tests {
 
     #[test]
     fn test_ok_multiple_times() {
-        let scheduler = Scheduler::new(Config::default(), Timestamp::day_cut_off());
+        let scheduler = scheduler();
         let mut card = Card::default();
 
         for _ in 1..1000 {
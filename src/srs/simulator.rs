@@ -0,0 +1,384 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue, MemoryState};
+use crate::srs::config::Config;
+use crate::srs::fsrs::{initial_memory_state, next_memory_state, retrievability};
+use crate::srs::registry::Registry;
+use crate::srs::scheduler::{Choice, Sched};
+use crate::svc::clock::{Clock, FixedClock};
+
+// chance of getting a card right during learning steps with the FSRS model
+const LEARN_RECALL: f32 = 0.9;
+
+// bounds on the input, so a simulation can't run or allocate without limit
+const MAX_DECK_SIZE: u32 = 100_000;
+const MAX_DAYS: u32 = 36_500;
+
+/// How likely the simulated user is to remember a card.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecallModel {
+    /// Every answer is remembered with the same probability.
+    Constant { probability: f32 },
+    /// Reviews are remembered with the FSRS retrievability of the card,
+    /// using `Config::fsrs_weights`, whichever algorithm schedules it.
+    Fsrs,
+}
+
+impl Default for RecallModel {
+    fn default() -> Self {
+        RecallModel::Constant { probability: 0.9 }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SimulationInput {
+    pub deck_size: u32,
+    pub new_per_day: u32,
+    pub days: u32,
+    #[serde(default)]
+    pub recall: RecallModel,
+    #[serde(default = "default_seconds_per_review")]
+    pub seconds_per_review: f32,
+    #[serde(default = "default_seconds_per_new")]
+    pub seconds_per_new: f32,
+    // seeds whether each answer is remembered
+    #[serde(default)]
+    pub seed: u64,
+}
+
+impl SimulationInput {
+    /// Check the deck and days are within bounds, and the recall
+    /// probability and seconds are usable.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| Err(Error::InvalidInput(format!("simulation input: {}", msg)));
+        if self.deck_size > MAX_DECK_SIZE {
+            return invalid(format!("deck_size must be at most {}", MAX_DECK_SIZE));
+        }
+        if self.days > MAX_DAYS {
+            return invalid(format!("days must be at most {}", MAX_DAYS));
+        }
+        if let RecallModel::Constant { probability } = self.recall {
+            if !(0.0..=1.0).contains(&probability) {
+                return invalid("recall probability must be between 0 and 1".to_string());
+            }
+        }
+        for (name, seconds) in &[
+            ("seconds_per_review", self.seconds_per_review),
+            ("seconds_per_new", self.seconds_per_new),
+        ] {
+            if !(seconds.is_finite() && *seconds >= 0.0) {
+                return invalid(format!("{} must not be negative", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_seconds_per_review() -> f32 {
+    10.0
+}
+
+fn default_seconds_per_new() -> f32 {
+    20.0
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SimulatedDay {
+    pub day: u32,
+    // answers to cards in the review queue
+    pub reviews: u32,
+    pub new_cards: u32,
+    // answers to cards in learning or relearning
+    pub learning: u32,
+    pub seconds: f32,
+    // share of reviews that were remembered
+    pub retention: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Simulation {
+    pub days: Vec<SimulatedDay>,
+    pub total_seconds: f32,
+    pub average_retention: f32,
+}
+
+struct Study<'a> {
+    recall: &'a RecallModel,
+    weights: &'a [f32],
+}
+
+struct SimulatedCard {
+    card: Card,
+    memory: Option<(MemoryState, u32)>,
+}
+
+/// Study a deck of new cards for `input.days` days with the registry's
+/// scheduler for `config.algorithm`, starting at the clock's current time,
+/// and report the daily workload.
+pub fn simulate(
+    registry: &Registry,
+    config: &Config,
+    input: &SimulationInput,
+    clock: &dyn Clock,
+) -> Result<Simulation, Error> {
+    input.validate()?;
+    let config = config.clone().validated()?;
+    let start = clock.now();
+    let sim_clock = Rc::new(FixedClock::new(start, clock.minutes_west(start)));
+    let mut rng = ChaCha8Rng::seed_from_u64(input.seed);
+    let study = Study {
+        recall: &input.recall,
+        weights: &config.fsrs_weights,
+    };
+    let mut cards: Vec<SimulatedCard> = (0..input.deck_size)
+        .map(|i| SimulatedCard {
+            card: Card {
+                id: i as i64 + 1,
+                due: i as i64,
+                ..Card::default()
+            },
+            memory: None,
+        })
+        .collect();
+
+    let mut days = vec![];
+    for day in 0..input.days {
+        sim_clock.set(start + day as i64 * 86_400);
+        let sched = registry.build(config.clone(), Box::new(sim_clock.clone()))?;
+        let mut stats = SimulatedDay {
+            day,
+            ..SimulatedDay::default()
+        };
+        let mut remembered = 0;
+
+        // reviews and interday learning due today
+        for sim in cards.iter_mut() {
+            let due = matches!(sim.card.card_queue, CardQueue::Review | CardQueue::DayLearn)
+                && sim.card.due <= sched.day_today();
+            if !due {
+                continue;
+            }
+            let review = sim.card.card_queue == CardQueue::Review;
+            let recalled = answer(sim, sched.as_ref(), &study, day, &mut rng);
+            stats.seconds += input.seconds_per_review;
+            if review {
+                stats.reviews += 1;
+                remembered += recalled as u32;
+            } else {
+                stats.learning += 1;
+            }
+        }
+
+        // new cards in deck order
+        for sim in cards
+            .iter_mut()
+            .filter(|sim| sim.card.card_queue == CardQueue::New)
+            .take(input.new_per_day as usize)
+        {
+            answer(sim, sched.as_ref(), &study, day, &mut rng);
+            stats.new_cards += 1;
+            stats.seconds += input.seconds_per_new;
+        }
+
+        // learning steps that fall due before the day ends, soonest first
+        let mut learning: BinaryHeap<Reverse<(i64, usize)>> = cards
+            .iter()
+            .enumerate()
+            .filter(|(_, sim)| sim.card.card_queue == CardQueue::Learn)
+            .map(|(i, sim)| Reverse((sim.card.due, i)))
+            .collect();
+        for _ in 0..input.deck_size.saturating_mul(50) {
+            let i = match learning.pop() {
+                Some(Reverse((due, i))) if due < sched.day_cut_off() => i,
+                _ => break,
+            };
+            let sim = &mut cards[i];
+            sim_clock.set(sim_clock.now().max(sim.card.due));
+            answer(sim, sched.as_ref(), &study, day, &mut rng);
+            stats.learning += 1;
+            stats.seconds += input.seconds_per_review;
+            if sim.card.card_queue == CardQueue::Learn {
+                learning.push(Reverse((sim.card.due, i)));
+            }
+        }
+
+        if stats.reviews > 0 {
+            stats.retention = remembered as f32 / stats.reviews as f32;
+        }
+        days.push(stats);
+    }
+
+    let total_seconds = days.iter().map(|day| day.seconds).sum();
+    let reviews: u32 = days.iter().map(|day| day.reviews).sum();
+    let average_retention = if reviews > 0 {
+        days.iter()
+            .map(|day| day.retention * day.reviews as f32)
+            .sum::<f32>()
+            / reviews as f32
+    } else {
+        0.0
+    };
+    Ok(Simulation {
+        days,
+        total_seconds,
+        average_retention,
+    })
+}
+
+/// Answer a card as the simulated user would, returning whether it was
+/// remembered.
+fn answer(
+    sim: &mut SimulatedCard,
+    sched: &dyn Sched,
+    study: &Study,
+    day: u32,
+    rng: &mut ChaCha8Rng,
+) -> bool {
+    let probability = match study.recall {
+        RecallModel::Constant { probability } => *probability,
+        RecallModel::Fsrs => match sim.memory {
+            Some((state, last_day)) if day > last_day => {
+                retrievability((day - last_day) as f32, state.stability)
+            }
+            _ => LEARN_RECALL,
+        },
+    };
+    let recalled = rng.gen::<f32>() < probability;
+    let choice = if recalled { Choice::Ok } else { Choice::Again };
+
+    if let RecallModel::Fsrs = study.recall {
+        let weights = study.weights;
+        sim.memory = match sim.memory {
+            None => Some((initial_memory_state(weights, choice), day)),
+            Some((state, last_day)) if day > last_day => Some((
+                next_memory_state(weights, state, (day - last_day) as f32, choice),
+                day,
+            )),
+            memory => memory,
+        };
+    }
+
+    sched.answer_card(&mut sim.card, choice, 0);
+    if sim.card.card_queue == CardQueue::Suspended {
+        // keep studying leeches, like a user who unsuspends them
+        sched.unsuspend_card(&mut sim.card);
+    }
+    recalled
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::srs::test_util::NOW;
+
+    use super::*;
+
+    fn input() -> SimulationInput {
+        SimulationInput {
+            deck_size: 100,
+            new_per_day: 10,
+            days: 30,
+            recall: RecallModel::default(),
+            seconds_per_review: 10.0,
+            seconds_per_new: 20.0,
+            seed: 1,
+        }
+    }
+
+    #[test]
+    fn test_simulate() {
+        let clock = FixedClock::new(NOW, 0);
+        let result = simulate(&Registry::default(), &Config::default(), &input(), &clock).unwrap();
+        assert_eq!(result.days.len(), 30);
+
+        // 10 new cards a day until the deck runs out
+        let new_cards: Vec<u32> = result.days.iter().map(|day| day.new_cards).collect();
+        assert_eq!(&new_cards[..10], &[10; 10]);
+        assert_eq!(&new_cards[10..], &[0; 20]);
+
+        assert_eq!(result.days[0].reviews, 0);
+        assert!(result.days[0].learning >= 10);
+        assert!(result.days[1].reviews > 0);
+        assert!(result.average_retention > 0.8 && result.average_retention < 1.0);
+
+        let total: f32 = result.days.iter().map(|day| day.seconds).sum();
+        assert_eq!(result.total_seconds, total);
+
+        // Same seed, same result
+        assert_eq!(
+            simulate(&Registry::default(), &Config::default(), &input(), &clock).unwrap(),
+            result
+        );
+    }
+
+    #[test]
+    fn test_fsrs_recall() {
+        let clock = FixedClock::new(NOW, 0);
+        let input = SimulationInput {
+            recall: RecallModel::Fsrs,
+            ..input()
+        };
+        let config = Config {
+            algorithm: "fsrs".to_string(),
+            ..Config::default()
+        };
+        let result = simulate(&Registry::default(), &config, &input, &clock).unwrap();
+        assert!(result.average_retention > 0.7);
+
+        // SM-2 with a lower interval multiplier means more reviews
+        let sm2 = simulate(&Registry::default(), &Config::default(), &input, &clock).unwrap();
+        let fewer = Config {
+            interval_multiplier: 0.5,
+            ..Config::default()
+        };
+        let more = simulate(&Registry::default(), &fewer, &input, &clock).unwrap();
+        let reviews = |sim: &Simulation| sim.days.iter().map(|day| day.reviews).sum::<u32>();
+        assert!(reviews(&more) > reviews(&sm2));
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let clock = FixedClock::new(NOW, 0);
+        let invalid = |input: SimulationInput| {
+            let result = simulate(&Registry::default(), &Config::default(), &input, &clock);
+            match result {
+                Err(Error::InvalidInput(msg)) => msg,
+                _ => panic!("expected invalid input"),
+            }
+        };
+        assert!(invalid(SimulationInput {
+            deck_size: u32::MAX,
+            ..input()
+        })
+        .contains("deck_size"));
+        assert!(invalid(SimulationInput {
+            days: u32::MAX,
+            ..input()
+        })
+        .contains("days"));
+        assert!(invalid(SimulationInput {
+            recall: RecallModel::Constant { probability: 1.5 },
+            ..input()
+        })
+        .contains("probability"));
+        assert!(invalid(SimulationInput {
+            recall: RecallModel::Constant {
+                probability: f32::NAN
+            },
+            ..input()
+        })
+        .contains("probability"));
+        assert!(invalid(SimulationInput {
+            seconds_per_new: -1.0,
+            ..input()
+        })
+        .contains("seconds_per_new"));
+    }
+}
@@ -0,0 +1,285 @@
+use serde_json::json;
+use serde_repr::{Deserialize_repr, Serialize_repr};
+
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue, CardType};
+use crate::srs::config::{Config, MINIMUM_EASE};
+use crate::srs::review_log::ReviewLog;
+use crate::srs::scheduler::{Choice, Sched, Scheduler};
+use crate::svc::clock::Clock;
+
+/// Quality of a response in the textbook SM-2 algorithm.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[repr(i8)]
+pub enum Grade {
+    Blackout = 0,
+    Incorrect = 1,
+    // incorrect, but the answer seemed easy to recall
+    IncorrectEasy = 2,
+    // correct, with serious difficulty
+    CorrectHard = 3,
+    // correct, after hesitation
+    Correct = 4,
+    Perfect = 5,
+}
+
+impl From<Choice> for Grade {
+    fn from(choice: Choice) -> Self {
+        match choice {
+            Choice::Again => Grade::IncorrectEasy,
+            Choice::Hard => Grade::CorrectHard,
+            Choice::Ok => Grade::Correct,
+            Choice::Easy => Grade::Perfect,
+        }
+    }
+}
+
+impl From<Grade> for Choice {
+    fn from(grade: Grade) -> Self {
+        match grade {
+            Grade::Blackout | Grade::Incorrect | Grade::IncorrectEasy => Choice::Again,
+            Grade::CorrectHard => Choice::Hard,
+            Grade::Correct => Choice::Ok,
+            Grade::Perfect => Choice::Easy,
+        }
+    }
+}
+
+/// Wozniak's published SM-2: I(1) = 1, I(2) = 6, I(n) = I(n-1) * EF.
+///
+/// The E-Factor is kept in `ease_factor` (2.5 is 2500) and the repetition
+/// number in `scheduler_state`. Items graded below 4 are repeated the same
+/// day, in the learn queue, until they are graded 4 or 5.
+pub struct SuperMemo2 {
+    config: Config,
+    clock: Box<dyn Clock>,
+}
+
+impl SuperMemo2 {
+    pub fn new(config: Config, clock: Box<dyn Clock>) -> Result<Self, Error> {
+        Ok(Self {
+            config: config.validated()?,
+            clock,
+        })
+    }
+
+    fn repetition(card: &Card) -> i64 {
+        card.scheduler_state
+            .as_ref()
+            .and_then(|state| state["repetition"].as_i64())
+            .unwrap_or(0)
+    }
+
+    fn e_factor(&self, card: &Card) -> f32 {
+        let ease = if card.ease_factor == 0 {
+            self.config.initial_ease
+        } else {
+            card.ease_factor
+        };
+        ease as f32 / 1_000.0
+    }
+
+    /// EF' = EF + (0.1 - (5 - q) * (0.08 + (5 - q) * 0.02))
+    fn next_e_factor(e_factor: f32, grade: Grade) -> f32 {
+        let q = 5.0 - grade as i8 as f32;
+        (e_factor + (0.1 - q * (0.08 + q * 0.02))).max(MINIMUM_EASE as f32 / 1_000.0)
+    }
+
+    /// Repetition number, E-Factor and interval after answering a review.
+    fn next_review(&self, card: &Card, grade: Grade) -> (i64, f32, i32) {
+        let e_factor = self.e_factor(card);
+        // start again from I(1)
+        if grade < Grade::CorrectHard {
+            return (1, e_factor, 1);
+        }
+
+        let repetition = Self::repetition(card) + 1;
+        let e_factor = Self::next_e_factor(e_factor, grade);
+        let interval = match repetition {
+            1 => 1,
+            2 => 6,
+            _ => (card.interval as f32 * e_factor).round() as i32,
+        };
+        (
+            repetition,
+            e_factor,
+            interval.min(self.config.maximum_review_interval),
+        )
+    }
+
+    /// Answer with a quality from 0 to 5, which `answer_card` only has four
+    /// of.
+    pub fn answer_card_grade(&self, card: &mut Card, grade: Grade, time_taken: i32) -> ReviewLog {
+        let before = card.clone();
+        self.answer(card, grade);
+        ReviewLog::new(&before, card, grade.into(), self.clock.now(), time_taken)
+    }
+
+    pub fn next_interval_for_grade(&self, card: &Card, grade: Grade) -> i64 {
+        if grade < Grade::Correct {
+            // repeated today
+            0
+        } else if card.card_queue == CardQueue::Learn {
+            card.interval as i64 * 86_400
+        } else {
+            self.next_review(card, grade).2 as i64 * 86_400
+        }
+    }
+
+    fn answer(&self, card: &mut Card, grade: Grade) {
+        card.reps += 1;
+
+        // same day drill, which doesn't change the schedule
+        if card.card_queue == CardQueue::Learn {
+            if grade >= Grade::Correct {
+                card.card_type = CardType::Review;
+                card.card_queue = CardQueue::Review;
+                card.due = self.day_today() + card.interval as i64;
+            } else {
+                card.due = self.clock.now();
+            }
+            return;
+        }
+
+        let (repetition, e_factor, interval) = self.next_review(card, grade);
+        if grade < Grade::CorrectHard && card.card_type == CardType::Review {
+            card.lapses += 1;
+        }
+        card.scheduler_state = Some(json!({ "repetition": repetition }));
+        card.ease_factor = (e_factor * 1_000.0).round() as i32;
+        card.interval = interval;
+
+        if grade < Grade::Correct {
+            card.card_type = match card.card_type {
+                CardType::New | CardType::Learn => CardType::Learn,
+                _ => CardType::Relearn,
+            };
+            card.card_queue = CardQueue::Learn;
+            card.due = self.clock.now();
+        } else {
+            card.card_type = CardType::Review;
+            card.card_queue = CardQueue::Review;
+            card.due = self.day_today() + interval as i64;
+        }
+    }
+}
+
+impl Sched for SuperMemo2 {
+    fn next_interval(&self, card: &Card, choice: Choice) -> i64 {
+        self.next_interval_for_grade(card, choice.into())
+    }
+
+    fn answer_card(&self, card: &mut Card, choice: Choice, time_taken: i32) -> ReviewLog {
+        self.answer_card_grade(card, choice.into(), time_taken)
+    }
+
+    fn schedule_card_as_new(&self, card: &mut Card) {
+        card.schedule_as_new(0, self.config.initial_ease);
+        card.scheduler_state = None;
+    }
+
+    fn schedule_card_as_review(&self, card: &mut Card, min_days: i32, max_days: i32) {
+        let interval = Scheduler::random_days(card, min_days, max_days);
+        card.schedule_as_review(interval, self.day_today(), self.config.initial_ease);
+    }
+
+    fn now(&self) -> i64 {
+        self.clock.now()
+    }
+
+    fn day_cut_off(&self) -> i64 {
+        self.config.day_cut_off(self.clock.as_ref())
+    }
+
+    fn day_today(&self) -> i64 {
+        self.config.day_today(self.clock.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::srs::test_util::NOW;
+    use crate::svc::clock::FixedClock;
+
+    use super::*;
+
+    fn supermemo() -> SuperMemo2 {
+        SuperMemo2::new(Config::default(), Box::new(FixedClock::new(NOW, 0))).unwrap()
+    }
+
+    #[test]
+    fn test_e_factor() {
+        let ef = |grade| SuperMemo2::next_e_factor(2.5, grade);
+        assert!((ef(Grade::Perfect) - 2.6).abs() < 1e-5);
+        assert!((ef(Grade::Correct) - 2.5).abs() < 1e-5);
+        assert!((ef(Grade::CorrectHard) - 2.36).abs() < 1e-5);
+        assert!((ef(Grade::Blackout) - 1.7).abs() < 1e-5);
+        assert!((SuperMemo2::next_e_factor(1.3, Grade::CorrectHard) - 1.3).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_intervals() {
+        let sm = supermemo();
+        let mut card = Card::default();
+
+        sm.answer_card_grade(&mut card, Grade::Correct, 0);
+        assert_eq!(card.interval, 1);
+        assert_eq!(card.due, sm.day_today() + 1);
+        assert_eq!(card.card_queue, CardQueue::Review);
+
+        sm.answer_card_grade(&mut card, Grade::Correct, 0);
+        assert_eq!(card.interval, 6);
+
+        sm.answer_card_grade(&mut card, Grade::Perfect, 0);
+        assert_eq!(card.ease_factor, 2_600);
+        assert_eq!(card.interval, 16);
+
+        // 16 * 2.6
+        assert_eq!(
+            sm.next_interval_for_grade(&card, Grade::Correct),
+            42 * 86_400
+        );
+    }
+
+    #[test]
+    fn test_lapse_and_drill() {
+        let sm = supermemo();
+        let mut card = Card::default();
+        sm.answer_card_grade(&mut card, Grade::Correct, 0);
+        sm.answer_card_grade(&mut card, Grade::Correct, 0);
+        let ease = card.ease_factor;
+
+        // Forgotten: restart repetitions, keep the E-Factor
+        sm.answer_card_grade(&mut card, Grade::Incorrect, 0);
+        assert_eq!(card.interval, 1);
+        assert_eq!(card.ease_factor, ease);
+        assert_eq!(card.lapses, 1);
+        assert_eq!(card.card_type, CardType::Relearn);
+        assert_eq!(card.card_queue, CardQueue::Learn);
+        assert_eq!(card.due, NOW);
+
+        // Drilled until it's recalled well
+        sm.answer_card_grade(&mut card, Grade::CorrectHard, 0);
+        assert_eq!(card.card_queue, CardQueue::Learn);
+        sm.answer_card_grade(&mut card, Grade::Perfect, 0);
+        assert_eq!(card.card_queue, CardQueue::Review);
+        assert_eq!(card.due, sm.day_today() + 1);
+        assert_eq!(card.ease_factor, ease);
+
+        // Then 6 days again
+        sm.answer_card_grade(&mut card, Grade::Correct, 0);
+        assert_eq!(card.interval, 6);
+    }
+
+    #[test]
+    fn test_choice() {
+        let sm = supermemo();
+        let mut card = Card::default();
+        let log = sm.answer_card(&mut card, Choice::Ok, 0);
+        assert_eq!(log.choice, Choice::Ok);
+        assert_eq!(card.interval, 1);
+
+        let log = sm.answer_card_grade(&mut card, Grade::Blackout, 0);
+        assert_eq!(log.choice, Choice::Again);
+    }
+}
@@ -0,0 +1,33 @@
+//! Fixtures shared by the tests.
+
+use std::rc::Rc;
+
+use crate::srs::card::{Card, CardQueue, CardType};
+use crate::srs::config::Config;
+use crate::srs::scheduler::Scheduler;
+use crate::svc::clock::FixedClock;
+
+// 2021-03-01 12:00:00 UTC
+pub const NOW: i64 = 1_614_600_000;
+
+/// The default scheduler, at `NOW` in UTC.
+pub fn scheduler() -> Scheduler {
+    Scheduler::new(Config::default(), Box::new(FixedClock::new(NOW, 0))).unwrap()
+}
+
+/// The default scheduler, on a clock the test can move.
+pub fn scheduler_on(clock: &Rc<FixedClock>) -> Scheduler {
+    Scheduler::new(Config::default(), Box::new(clock.clone())).unwrap()
+}
+
+/// A review card with a one day interval, due on day `due`.
+pub fn review(due: i64) -> Card {
+    Card {
+        card_type: CardType::Review,
+        card_queue: CardQueue::Review,
+        due,
+        interval: 1,
+        ease_factor: 2_500,
+        ..Card::default()
+    }
+}
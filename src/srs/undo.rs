@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::srs::card::Card;
+
+/// Snapshot of a card taken just before it was answered, serialized as
+/// `{ card }`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UndoToken {
+    card: Card,
+}
+
+impl UndoToken {
+    pub fn new(card: &Card) -> Self {
+        Self { card: card.clone() }
+    }
+
+    pub fn card_id(&self) -> i64 {
+        self.card.id
+    }
+
+    /// The card exactly as it was before the answer.
+    pub fn restore(self) -> Card {
+        self.card
+    }
+}
+
+/// Keeps the last `limit` undo tokens, dropping the oldest first.
+pub struct UndoStack {
+    limit: usize,
+    tokens: VecDeque<UndoToken>,
+}
+
+impl UndoStack {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            tokens: VecDeque::with_capacity(limit),
+        }
+    }
+
+    pub fn push(&mut self, token: UndoToken) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.tokens.len() == self.limit {
+            self.tokens.pop_front();
+        }
+        self.tokens.push_back(token);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoToken> {
+        self.tokens.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_limit() {
+        let mut stack = UndoStack::new(2);
+        for id in 1..=3 {
+            stack.push(UndoToken::new(&Card {
+                id,
+                ..Card::default()
+            }));
+        }
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack.pop().unwrap().card_id(), 3);
+        assert_eq!(stack.pop().unwrap().card_id(), 2);
+        assert!(stack.pop().is_none());
+
+        let mut stack = UndoStack::new(0);
+        stack.push(UndoToken::new(&Card::default()));
+        assert!(stack.is_empty());
+    }
+}
@@ -0,0 +1,407 @@
+use std::path::Path;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::error::Error;
+use crate::srs::card::{Card, CardQueue};
+use crate::srs::config::Config;
+use crate::srs::review_log::ReviewLog;
+use crate::srs::scheduler::{Choice, Sched};
+
+// Each entry upgrades the schema by one version; `user_version` records how
+// many have been applied. Append new ones, never edit old ones.
+const MIGRATIONS: &[&str] = &[
+    // cards are stored whole in `data`, with the columns they're looked up
+    // by copied out next to them
+    "CREATE TABLE cards (
+        id INTEGER PRIMARY KEY,
+        note_id INTEGER NOT NULL,
+        deck_id INTEGER NOT NULL,
+        card_queue INTEGER NOT NULL,
+        due INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX ix_cards_queue_due ON cards (card_queue, due);
+    CREATE INDEX ix_cards_note ON cards (note_id);
+    CREATE INDEX ix_cards_deck ON cards (deck_id);
+    CREATE TABLE configs (
+        id INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );",
+    "CREATE TABLE review_logs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        card_id INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX ix_review_logs_card ON review_logs (card_id, timestamp);",
+];
+
+fn storage_error(err: impl ToString) -> Error {
+    Error::Storage(err.to_string())
+}
+
+/// Cards, deck configs and review logs in a SQLite file.
+pub struct SqliteStorage {
+    db: Connection,
+}
+
+impl SqliteStorage {
+    /// Open or create the file at `path`, upgrading its schema if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::with_connection(Connection::open(path).map_err(storage_error)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::with_connection(Connection::open_in_memory().map_err(storage_error)?)
+    }
+
+    fn with_connection(db: Connection) -> Result<Self, Error> {
+        let mut storage = Self { db };
+        storage.upgrade()?;
+        Ok(storage)
+    }
+
+    pub fn schema_version(&self) -> Result<usize, Error> {
+        let version: i64 = self
+            .db
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(storage_error)?;
+        Ok(version as usize)
+    }
+
+    fn upgrade(&mut self) -> Result<(), Error> {
+        let version = self.schema_version()?;
+        if version > MIGRATIONS.len() {
+            return Err(Error::Storage(format!(
+                "schema version {} is newer than this library supports",
+                version
+            )));
+        }
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.db.transaction().map_err(storage_error)?;
+            tx.execute_batch(migration).map_err(storage_error)?;
+            tx.pragma_update(None, "user_version", (index + 1) as i64)
+                .map_err(storage_error)?;
+            tx.commit().map_err(storage_error)?;
+        }
+        Ok(())
+    }
+
+    /// Store a new card, giving it the next free id if it has none, and
+    /// return its id.
+    pub fn add_card(&mut self, mut card: Card) -> Result<i64, Error> {
+        card.validate()?;
+        if card.id == 0 {
+            card.id = self
+                .db
+                .query_row("SELECT COALESCE(MAX(id), 0) + 1 FROM cards", [], |row| {
+                    row.get(0)
+                })
+                .map_err(storage_error)?;
+        } else if self.card(card.id)?.is_some() {
+            return Err(Error::InvalidCard(format!(
+                "card {} is already stored",
+                card.id
+            )));
+        }
+        self.db
+            .execute(
+                "INSERT INTO cards (id, note_id, deck_id, card_queue, due, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    card.id,
+                    card.note_id,
+                    card.deck_id,
+                    card.card_queue as i8,
+                    card.due,
+                    serde_json::to_string(&card).map_err(storage_error)?
+                ],
+            )
+            .map_err(storage_error)?;
+        Ok(card.id)
+    }
+
+    pub fn update_card(&self, card: &Card) -> Result<(), Error> {
+        card.validate()?;
+        write_card(&self.db, card)
+    }
+
+    pub fn card(&self, id: i64) -> Result<Option<Card>, Error> {
+        let data: Option<String> = self
+            .db
+            .query_row("SELECT data FROM cards WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(storage_error)?;
+        data.map(|data| serde_json::from_str(&data))
+            .transpose()
+            .map_err(storage_error)
+    }
+
+    pub fn cards_in_deck(&self, deck_id: i64) -> Result<Vec<Card>, Error> {
+        self.query_cards(
+            "SELECT data FROM cards WHERE deck_id = ?1 ORDER BY id",
+            [deck_id],
+        )
+    }
+
+    /// Cards due before the scheduler's next day cut off: intraday learning
+    /// and previews by due time, then reviews and interday learning due
+    /// today.
+    pub fn due_cards(&self, sched: &dyn Sched) -> Result<Vec<Card>, Error> {
+        let last_second = sched.day_cut_off() - 1;
+        let today = sched.day_today();
+        let mut cards = vec![];
+        for (queue, due) in &[
+            (CardQueue::Learn, last_second),
+            (CardQueue::Preview, last_second),
+            (CardQueue::Review, today),
+            (CardQueue::DayLearn, today),
+        ] {
+            cards.extend(self.query_cards(
+                "SELECT data FROM cards WHERE card_queue = ?1 AND due <= ?2 ORDER BY due, id",
+                params![*queue as i8, due],
+            )?);
+        }
+        Ok(cards)
+    }
+
+    fn query_cards<P: rusqlite::Params>(&self, sql: &str, params: P) -> Result<Vec<Card>, Error> {
+        let mut stmt = self.db.prepare_cached(sql).map_err(storage_error)?;
+        let rows = stmt
+            .query_map(params, |row| row.get::<_, String>(0))
+            .map_err(storage_error)?;
+        let mut cards = vec![];
+        for data in rows {
+            cards.push(serde_json::from_str(&data.map_err(storage_error)?).map_err(storage_error)?);
+        }
+        Ok(cards)
+    }
+
+    pub fn set_config(&self, id: i64, config: &Config) -> Result<(), Error> {
+        self.db
+            .execute(
+                "INSERT OR REPLACE INTO configs (id, data) VALUES (?1, ?2)",
+                params![id, serde_json::to_string(config).map_err(storage_error)?],
+            )
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    pub fn config(&self, id: i64) -> Result<Option<Config>, Error> {
+        let data: Option<String> = self
+            .db
+            .query_row("SELECT data FROM configs WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(storage_error)?;
+        data.map(|data| serde_json::from_str(&data))
+            .transpose()
+            .map_err(storage_error)
+    }
+
+    pub fn add_review_log(&self, log: &ReviewLog) -> Result<(), Error> {
+        write_review_log(&self.db, log)
+    }
+
+    /// Review logs of a card, oldest first.
+    pub fn review_logs(&self, card_id: i64) -> Result<Vec<ReviewLog>, Error> {
+        let mut stmt = self
+            .db
+            .prepare_cached(
+                "SELECT data FROM review_logs WHERE card_id = ?1 ORDER BY timestamp, id",
+            )
+            .map_err(storage_error)?;
+        let rows = stmt
+            .query_map([card_id], |row| row.get::<_, String>(0))
+            .map_err(storage_error)?;
+        let mut logs = vec![];
+        for data in rows {
+            logs.push(serde_json::from_str(&data.map_err(storage_error)?).map_err(storage_error)?);
+        }
+        Ok(logs)
+    }
+
+    /// Answer a stored card, saving the card and its review log together.
+    pub fn answer_card(
+        &mut self,
+        card_id: i64,
+        choice: Choice,
+        time_taken: i32,
+        sched: &dyn Sched,
+    ) -> Result<ReviewLog, Error> {
+        let mut card = self
+            .card(card_id)?
+            .ok_or_else(|| Error::InvalidCard(format!("no card with id {}", card_id)))?;
+        let log = sched.answer_card(&mut card, choice, time_taken);
+
+        let tx = self.db.transaction().map_err(storage_error)?;
+        write_card(&tx, &card)?;
+        write_review_log(&tx, &log)?;
+        tx.commit().map_err(storage_error)?;
+        Ok(log)
+    }
+}
+
+fn write_card(db: &Connection, card: &Card) -> Result<(), Error> {
+    let updated = db
+        .execute(
+            "UPDATE cards SET note_id = ?2, deck_id = ?3, card_queue = ?4, due = ?5, data = ?6
+             WHERE id = ?1",
+            params![
+                card.id,
+                card.note_id,
+                card.deck_id,
+                card.card_queue as i8,
+                card.due,
+                serde_json::to_string(card).map_err(storage_error)?
+            ],
+        )
+        .map_err(storage_error)?;
+    if updated == 0 {
+        return Err(Error::InvalidCard(format!("no card with id {}", card.id)));
+    }
+    Ok(())
+}
+
+fn write_review_log(db: &Connection, log: &ReviewLog) -> Result<(), Error> {
+    db.execute(
+        "INSERT INTO review_logs (card_id, timestamp, data) VALUES (?1, ?2, ?3)",
+        params![
+            log.card_id,
+            log.timestamp,
+            serde_json::to_string(log).map_err(storage_error)?
+        ],
+    )
+    .map_err(storage_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::srs::card::CardType;
+    use crate::srs::test_util::{review, scheduler, NOW};
+
+    use super::*;
+
+    #[test]
+    fn test_migrations() {
+        let path = std::env::temp_dir().join(format!("sm2-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.schema_version(), Ok(MIGRATIONS.len()));
+        let id = storage.add_card(Card::default()).unwrap();
+        drop(storage);
+
+        // opening again keeps the data and doesn't rerun migrations
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.schema_version(), Ok(MIGRATIONS.len()));
+        assert!(storage.card(id).unwrap().is_some());
+        drop(storage);
+
+        let db = Connection::open(&path).unwrap();
+        db.pragma_update(None, "user_version", 99).unwrap();
+        drop(db);
+        assert!(matches!(SqliteStorage::open(&path), Err(Error::Storage(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cards_and_configs() {
+        let mut storage = SqliteStorage::open_in_memory().unwrap();
+        assert_eq!(storage.add_card(Card::default()), Ok(1));
+        assert_eq!(storage.add_card(Card::default()), Ok(2));
+        assert!(matches!(
+            storage.add_card(Card {
+                id: 2,
+                ..Card::default()
+            }),
+            Err(Error::InvalidCard(_))
+        ));
+
+        let mut card = storage.card(2).unwrap().unwrap();
+        card.deck_id = 5;
+        storage.update_card(&card).unwrap();
+        let ids: Vec<i64> = storage
+            .cards_in_deck(5)
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(ids, vec![2]);
+        assert!(storage
+            .update_card(&Card {
+                id: 9,
+                ..Card::default()
+            })
+            .is_err());
+
+        let config = Config {
+            new_per_day: 5,
+            ..Config::default()
+        };
+        storage.set_config(1, &config).unwrap();
+        assert_eq!(storage.config(1).unwrap().unwrap().new_per_day, 5);
+        assert!(storage.config(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_due_cards() {
+        let sched = scheduler();
+        let today = sched.day_today();
+        let mut storage = SqliteStorage::open_in_memory().unwrap();
+        storage.add_card(review(today + 1)).unwrap();
+        let overdue = storage.add_card(review(today - 3)).unwrap();
+        let due = storage.add_card(review(today)).unwrap();
+        let learning = storage
+            .add_card(Card {
+                card_type: CardType::Learn,
+                card_queue: CardQueue::Learn,
+                due: NOW + 60,
+                ..Card::default()
+            })
+            .unwrap();
+        storage.add_card(Card::default()).unwrap();
+
+        let ids: Vec<i64> = storage
+            .due_cards(&sched)
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(ids, vec![learning, overdue, due]);
+
+        let plan: String = storage
+            .db
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT data FROM cards WHERE card_queue = 2 AND due <= 0",
+                [],
+                |row| row.get(3),
+            )
+            .unwrap();
+        assert!(plan.contains("ix_cards_queue_due"));
+    }
+
+    #[test]
+    fn test_answer_card() {
+        let sched = scheduler();
+        let mut storage = SqliteStorage::open_in_memory().unwrap();
+        let id = storage.add_card(review(sched.day_today())).unwrap();
+
+        let log = storage.answer_card(id, Choice::Ok, 3_000, &sched).unwrap();
+        assert_eq!(storage.review_logs(id), Ok(vec![log]));
+        let card = storage.card(id).unwrap().unwrap();
+        assert!(card.due > sched.day_today());
+        assert!(storage.due_cards(&sched).unwrap().is_empty());
+
+        assert!(matches!(
+            storage.answer_card(id + 1, Choice::Ok, 0, &sched),
+            Err(Error::InvalidCard(_))
+        ));
+        assert_eq!(storage.review_logs(id + 1), Ok(vec![]));
+    }
+}
@@ -0,0 +1,117 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use chrono::Utc;
+
+use crate::error::Error;
+use crate::svc::timestamp::{check_timestamp, local_minutes_west_for_stamp};
+
+/// Source of the current time for everything that schedules cards.
+pub trait Clock {
+    /// Seconds since the epoch.
+    fn now(&self) -> i64;
+
+    /// Offset west of UTC in minutes at the given timestamp.
+    fn minutes_west(&self, stamp: i64) -> i32;
+}
+
+/// Reads the host's wall clock and local timezone.
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp()
+    }
+
+    fn minutes_west(&self, stamp: i64) -> i32 {
+        local_minutes_west_for_stamp(stamp)
+    }
+}
+
+/// A clock that only moves when told to, eg for tests or for replaying
+/// reviews "as of" a past time.
+#[derive(Clone, Default)]
+pub struct FixedClock {
+    now: Cell<i64>,
+    minutes_west: i32,
+}
+
+impl FixedClock {
+    pub fn new(now: i64, minutes_west: i32) -> Self {
+        Self {
+            now: Cell::new(now),
+            minutes_west,
+        }
+    }
+
+    /// Like `new`, for times given by the user, which must be between
+    /// years 1 and 9999 and less than a day from UTC.
+    pub fn try_new(now: i64, minutes_west: i32) -> Result<Self, Error> {
+        check_timestamp("now", now)?;
+        if minutes_west.abs() >= 24 * 60 {
+            return Err(Error::InvalidInput(format!(
+                "minutes_west {} is not less than a day",
+                minutes_west
+            )));
+        }
+        Ok(Self::new(now, minutes_west))
+    }
+
+    pub fn set(&self, now: i64) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, secs: i64) {
+        self.now.set(self.now.get() + secs);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> i64 {
+        self.now.get()
+    }
+
+    fn minutes_west(&self, _stamp: i64) -> i32 {
+        self.minutes_west
+    }
+}
+
+/// Lets a clock be shared, eg to move a `FixedClock` that a scheduler owns.
+impl<C: Clock + ?Sized> Clock for Rc<C> {
+    fn now(&self) -> i64 {
+        self.as_ref().now()
+    }
+
+    fn minutes_west(&self, stamp: i64) -> i32 {
+        self.as_ref().minutes_west(stamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_clock() {
+        let clock = FixedClock::new(1_000, -600);
+        assert_eq!(clock.now(), 1_000);
+        assert_eq!(clock.minutes_west(clock.now()), -600);
+
+        clock.advance(60);
+        assert_eq!(clock.now(), 1_060);
+
+        clock.set(5);
+        assert_eq!(clock.now(), 5);
+
+        assert!(FixedClock::try_new(1_000, -600).is_ok());
+        assert!(matches!(
+            FixedClock::try_new(i64::MAX, 0),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            FixedClock::try_new(1_000, 24 * 60),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+}
@@ -1,2 +1,4 @@
+pub mod clock;
 pub mod timespan;
 pub mod timestamp;
+pub mod timezone;
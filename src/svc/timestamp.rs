@@ -1,56 +1,175 @@
-use chrono::{DateTime, Duration, FixedOffset, Local, TimeZone, Utc};
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDate, TimeZone};
 
-pub struct Timestamp(i64);
+use crate::error::Error;
+use crate::svc::clock::Clock;
+use crate::svc::timezone::Zone;
+
+// hour of the day when a new day starts, unless configured otherwise
+pub const DEFAULT_ROLLOVER_HOUR: u8 = 4;
+
+// timestamps from 0001-01-01 to 9999-12-31 UTC, which every timezone can
+// place on a calendar
+pub const MIN_TIMESTAMP: i64 = -62_135_596_800;
+pub const MAX_TIMESTAMP: i64 = 253_402_300_799;
+
+/// Check a timestamp given as `name` is between years 1 and 9999.
+pub fn check_timestamp(name: &str, secs: i64) -> Result<(), Error> {
+    if (MIN_TIMESTAMP..=MAX_TIMESTAMP).contains(&secs) {
+        Ok(())
+    } else {
+        Err(out_of_range(name, secs))
+    }
+}
+
+fn out_of_range(name: &str, secs: i64) -> Error {
+    Error::InvalidInput(format!("{} {} is not between years 1 and 9999", name, secs))
+}
+
+pub struct Timestamp;
 
 impl Timestamp {
-    pub fn now() -> i64 {
-        now()
+    pub fn day_cut_off(clock: &dyn Clock) -> i64 {
+        Self::day_cut_off_at(clock, DEFAULT_ROLLOVER_HOUR, None)
     }
 
-    pub fn day_cut_off() -> i64 {
-        get_next_day(
-            now(),
-            local_minutes_west_for_stamp(Utc::now().timestamp()),
-            4,
-        )
-        .timestamp()
+    /// The next rollover at `rollover_hour` in `zone`, or in the clock's
+    /// timezone if there's none.
+    pub fn day_cut_off_at(clock: &dyn Clock, rollover_hour: u8, zone: Option<&Zone>) -> i64 {
+        let now = clock.now();
+        let next_day = match zone {
+            Some(zone) => zone.next_day_at(now, rollover_hour),
+            None => {
+                get_next_day(now, clock.minutes_west(now), rollover_hour).map(|d| d.timestamp())
+            }
+        };
+        // the next UTC midnight, for clocks outside the calendar
+        next_day.unwrap_or_else(|_| (now.div_euclid(86_400) + 1).saturating_mul(86_400))
     }
-}
 
-pub fn now() -> i64 {
-    Utc::now().timestamp()
+    /// Days since the day `created_secs` fell on where it was created,
+    /// counting today by `rollover_hour` in `zone`, or in the clock's
+    /// timezone if there's none.
+    pub fn days_elapsed_at(
+        clock: &dyn Clock,
+        created_secs: i64,
+        created_mins_west: i32,
+        rollover_hour: u8,
+        zone: Option<&Zone>,
+    ) -> i64 {
+        let now = clock.now();
+        let elapsed = match zone {
+            Some(zone) => zone.days_elapsed(created_secs, created_mins_west, now, rollover_hour),
+            None => days_elapsed(
+                created_secs,
+                created_mins_west,
+                now,
+                &fixed_offset_from_minutes(clock.minutes_west(now)),
+                rollover_hour,
+            ),
+        };
+        // UTC days, for clocks outside the calendar
+        elapsed.unwrap_or_else(|_| now.div_euclid(86_400) - created_secs.div_euclid(86_400))
+    }
 }
 
 /// - now_secs is a timestamp of the current time
 /// - now_mins_west is the current offset west of UTC
 /// - rollover_hour is the hour of the day the rollover happens (eg 4 for 4am)
-pub fn get_next_day(now_secs: i64, now_mins_west: i32, rollover_hour: u8) -> DateTime<FixedOffset> {
-    let now_datetime = fixed_offset_from_minutes(now_mins_west).timestamp(now_secs, 0);
-    let today = now_datetime.date();
+pub fn get_next_day(
+    now_secs: i64,
+    now_mins_west: i32,
+    rollover_hour: u8,
+) -> Result<DateTime<FixedOffset>, Error> {
+    get_next_day_in(
+        now_secs,
+        &fixed_offset_from_minutes(now_mins_west),
+        rollover_hour,
+    )
+}
+
+/// Like `get_next_day`, but in a timezone whose offset may change, so days
+/// around DST transitions are 23 or 25 hours long.
+pub fn get_next_day_in<Tz: TimeZone>(
+    now_secs: i64,
+    tz: &Tz,
+    rollover_hour: u8,
+) -> Result<DateTime<Tz>, Error> {
+    let now_datetime = tz
+        .timestamp_opt(now_secs, 0)
+        .single()
+        .ok_or_else(|| out_of_range("now", now_secs))?;
+    let today = now_datetime.date_naive();
 
     // rollover
-    let rollover_today_datetime = today.and_hms(rollover_hour as u32, 0, 0);
+    let rollover_today_datetime = rollover_on(tz, today, rollover_hour);
     let rollover_passed = rollover_today_datetime <= now_datetime;
 
     if rollover_passed {
-        rollover_today_datetime + Duration::days(1)
+        let tomorrow = today
+            .succ_opt()
+            .ok_or_else(|| out_of_range("now", now_secs))?;
+        Ok(rollover_on(tz, tomorrow, rollover_hour))
     } else {
-        rollover_today_datetime
+        Ok(rollover_today_datetime)
+    }
+}
+
+/// Local days between creation and now. The creation day is fixed by the
+/// offset it was made at, so changing timezones doesn't move today by more
+/// than the change in local date.
+pub fn days_elapsed<Tz: TimeZone>(
+    created_secs: i64,
+    created_mins_west: i32,
+    now_secs: i64,
+    tz: &Tz,
+    rollover_hour: u8,
+) -> Result<i64, Error> {
+    let created = fixed_offset_from_minutes(created_mins_west)
+        .timestamp_opt(created_secs, 0)
+        .single()
+        .ok_or_else(|| out_of_range("collection_created", created_secs))?;
+    let now = tz
+        .timestamp_opt(now_secs, 0)
+        .single()
+        .ok_or_else(|| out_of_range("now", now_secs))?;
+    Ok((local_day(&now, rollover_hour) - local_day(&created, rollover_hour)).num_days())
+}
+
+/// The date a time counts towards, when days start at `rollover_hour`.
+fn local_day<Tz: TimeZone>(datetime: &DateTime<Tz>, rollover_hour: u8) -> NaiveDate {
+    (datetime.naive_local() - Duration::hours(rollover_hour as i64)).date()
+}
+
+/// The rollover hour on a date. When DST skips it the day starts at the
+/// first local time after it, and when DST repeats it, at the first of the
+/// two.
+fn rollover_on<Tz: TimeZone>(tz: &Tz, date: NaiveDate, rollover_hour: u8) -> DateTime<Tz> {
+    let mut local = date.and_hms_opt(rollover_hour as u32, 0, 0).unwrap();
+    loop {
+        if let Some(datetime) = tz.from_local_datetime(&local).earliest() {
+            return datetime;
+        }
+        local += Duration::minutes(30);
     }
 }
 
 fn fixed_offset_from_minutes(minutes_west: i32) -> FixedOffset {
-    let bounded_minutes = minutes_west.max(-23 * 60).min(23 * 60);
-    FixedOffset::west(bounded_minutes * 60)
+    let bounded_minutes = minutes_west.clamp(-23 * 60, 23 * 60);
+    FixedOffset::west_opt(bounded_minutes * 60).unwrap()
 }
 
-fn local_minutes_west_for_stamp(stamp: i64) -> i32 {
-    Local.timestamp(stamp, 0).offset().utc_minus_local() / 60
+/// The host's offset, or UTC for times outside the calendar.
+pub(crate) fn local_minutes_west_for_stamp(stamp: i64) -> i32 {
+    Local
+        .timestamp_opt(stamp, 0)
+        .single()
+        .map_or(0, |datetime| datetime.offset().utc_minus_local() / 60)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::svc::clock::FixedClock;
     use chrono::{Local, TimeZone, Utc};
 
     // static timezone for tests
@@ -72,38 +191,115 @@ mod tests {
         assert_eq!(local_minutes_west_for_stamp(Utc::now().timestamp()), -480);
     }
 
+    #[test]
+    fn next_day_across_dst() {
+        let new_york = chrono_tz::America::New_York;
+        let at = |y, m, d, h| new_york.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap();
+
+        // the day DST starts is 23 hours long
+        let next = get_next_day_in(at(2021, 3, 13, 12).timestamp(), &new_york, 4).unwrap();
+        assert_eq!(next, at(2021, 3, 14, 4));
+        let after = get_next_day_in(next.timestamp(), &new_york, 4).unwrap();
+        assert_eq!(after.timestamp() - next.timestamp(), 24 * 3_600);
+        let before = get_next_day_in(at(2021, 3, 13, 4).timestamp() - 1, &new_york, 4).unwrap();
+        assert_eq!(next.timestamp() - before.timestamp(), 23 * 3_600);
+
+        // 2am doesn't exist that day, so the day starts at 3am
+        let next = get_next_day_in(at(2021, 3, 14, 1).timestamp(), &new_york, 2).unwrap();
+        assert_eq!(next, at(2021, 3, 14, 3));
+
+        // 1am happens twice when DST ends; the first one counts
+        let next = get_next_day_in(at(2021, 11, 7, 0).timestamp(), &new_york, 1).unwrap();
+        assert_eq!(next.timestamp(), 1_636_261_200);
+        let next = get_next_day_in(next.timestamp(), &new_york, 1).unwrap();
+        assert_eq!(next, at(2021, 11, 8, 1));
+    }
+
+    #[test]
+    fn outside_the_calendar() {
+        assert!(check_timestamp("now", MAX_TIMESTAMP).is_ok());
+        assert!(matches!(
+            check_timestamp("now", MAX_TIMESTAMP + 1),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(get_next_day(i64::MAX, 0, 4).is_err());
+        assert!(days_elapsed(0, 0, i64::MIN, &Utc, 4).is_err());
+
+        // schedulers fall back to UTC days instead of panicking
+        let clock = FixedClock::new(i64::MAX, 0);
+        assert_eq!(Timestamp::day_cut_off(&clock), i64::MAX);
+        let clock = FixedClock::new(-86_400 * 1_000_000_000_000, 0);
+        assert_eq!(
+            Timestamp::days_elapsed_at(&clock, 86_400, 0, 4, None),
+            -1_000_000_000_001
+        );
+    }
+
+    #[test]
+    fn days_since_creation() {
+        let rollover = 4;
+        // 2021-03-01 10:00 in Sydney
+        let created = 1_614_556_800;
+        let sydney = fixed_offset_from_minutes(AEST_MINS_WEST);
+        let elapsed = |now, tz: &FixedOffset| {
+            days_elapsed(created, AEST_MINS_WEST, now, tz, rollover).unwrap()
+        };
+
+        assert_eq!(elapsed(created, &sydney), 0);
+        // 2021-03-11 03:00 and 12:00 in Sydney
+        assert_eq!(elapsed(1_615_392_000, &sydney), 9);
+        assert_eq!(elapsed(1_615_424_400, &sydney), 10);
+
+        // 2021-03-11 12:00 in Los Angeles is still the 10th day, even though
+        // the epoch day of the next cut off has moved on
+        let los_angeles = fixed_offset_from_minutes(480);
+        let now = 1_615_492_800;
+        assert_eq!(elapsed(now, &los_angeles), 10);
+        let sydney_cut_off = get_next_day(1_615_424_400, AEST_MINS_WEST, rollover).unwrap();
+        let los_angeles_cut_off = get_next_day(now, 480, rollover).unwrap();
+        assert_eq!(
+            los_angeles_cut_off.timestamp() / 86_400 - sydney_cut_off.timestamp() / 86_400,
+            1
+        );
+    }
+
     #[test]
     fn next_day_at() {
         let rollhour = 4;
 
         // before the rollover, the next day should be later on the same day
-        let now = Local.ymd(2019, 1, 3).and_hms(2, 0, 0);
-        let next_day_at = Local.ymd(2019, 1, 3).and_hms(rollhour, 0, 0);
+        let now = Local.with_ymd_and_hms(2019, 1, 3, 2, 0, 0).unwrap();
+        let next_day_at = Local.with_ymd_and_hms(2019, 1, 3, rollhour, 0, 0).unwrap();
         let today = get_next_day(
             now.timestamp(),
             now.offset().utc_minus_local() / 60,
             rollhour as u8,
-        );
+        )
+        .unwrap();
         assert_eq!(today.timestamp(), next_day_at.timestamp());
 
         // after the rollover, the next day should be the next day
-        let now = Local.ymd(2019, 1, 3).and_hms(rollhour, 0, 0);
-        let next_day_at = Local.ymd(2019, 1, 4).and_hms(rollhour, 0, 0);
+        let now = Local.with_ymd_and_hms(2019, 1, 3, rollhour, 0, 0).unwrap();
+        let next_day_at = Local.with_ymd_and_hms(2019, 1, 4, rollhour, 0, 0).unwrap();
         let today = get_next_day(
             now.timestamp(),
             now.offset().utc_minus_local() / 60,
             rollhour as u8,
-        );
+        )
+        .unwrap();
         assert_eq!(today.timestamp(), next_day_at.timestamp());
 
         // after the rollover, the next day should be the next day
-        let now = Local.ymd(2019, 1, 3).and_hms(rollhour + 3, 0, 0);
-        let next_day_at = Local.ymd(2019, 1, 4).and_hms(rollhour, 0, 0);
+        let now = Local
+            .with_ymd_and_hms(2019, 1, 3, rollhour + 3, 0, 0)
+            .unwrap();
+        let next_day_at = Local.with_ymd_and_hms(2019, 1, 4, rollhour, 0, 0).unwrap();
         let today = get_next_day(
             now.timestamp(),
             now.offset().utc_minus_local() / 60,
             rollhour as u8,
-        );
+        )
+        .unwrap();
         assert_eq!(today.timestamp(), next_day_at.timestamp());
     }
 }
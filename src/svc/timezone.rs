@@ -0,0 +1,116 @@
+use chrono::{FixedOffset, Offset, TimeZone};
+use chrono_tz::Tz;
+
+use crate::error::Error;
+use crate::svc::timestamp::{days_elapsed, get_next_day_in};
+
+/// The user's timezone, when it shouldn't be taken from the clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl Zone {
+    /// Read an IANA name like "Europe/Berlin", "UTC", or a fixed offset like
+    /// "+05:30" or "-0800".
+    pub fn parse(name: &str) -> Result<Self, String> {
+        if name.starts_with('+') || name.starts_with('-') {
+            return parse_offset(name)
+                .map(Zone::Fixed)
+                .ok_or_else(|| format!("{} is not an offset like +05:30", name));
+        }
+        name.parse::<Tz>()
+            .map(Zone::Named)
+            .map_err(|_| format!("{} is not a known timezone", name))
+    }
+
+    /// Offset west of UTC in minutes at the given timestamp, or UTC for
+    /// times outside the calendar.
+    pub fn minutes_west(&self, stamp: i64) -> i32 {
+        let offset = match self {
+            Zone::Fixed(offset) => Some(*offset),
+            Zone::Named(tz) => tz
+                .timestamp_opt(stamp, 0)
+                .single()
+                .map(|datetime| datetime.offset().fix()),
+        };
+        offset.map_or(0, |offset| offset.utc_minus_local() / 60)
+    }
+
+    /// Timestamp of the next rollover at `rollover_hour` local time.
+    pub fn next_day_at(&self, now_secs: i64, rollover_hour: u8) -> Result<i64, Error> {
+        Ok(match self {
+            Zone::Fixed(offset) => get_next_day_in(now_secs, offset, rollover_hour)?.timestamp(),
+            Zone::Named(tz) => get_next_day_in(now_secs, tz, rollover_hour)?.timestamp(),
+        })
+    }
+
+    /// Local days in this zone since the day of `created_secs`.
+    pub fn days_elapsed(
+        &self,
+        created_secs: i64,
+        created_mins_west: i32,
+        now_secs: i64,
+        rollover_hour: u8,
+    ) -> Result<i64, Error> {
+        match self {
+            Zone::Fixed(offset) => days_elapsed(
+                created_secs,
+                created_mins_west,
+                now_secs,
+                offset,
+                rollover_hour,
+            ),
+            Zone::Named(tz) => {
+                days_elapsed(created_secs, created_mins_west, now_secs, tz, rollover_hour)
+            }
+        }
+    }
+}
+
+fn parse_offset(text: &str) -> Option<FixedOffset> {
+    let sign = if text.starts_with('-') { -1 } else { 1 };
+    let digits: String = text[1..].chars().filter(|c| *c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = match digits.len() {
+        1 | 2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (
+            digits[..2].parse::<i32>().ok()?,
+            digits[2..].parse::<i32>().ok()?,
+        ),
+        _ => return None,
+    };
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3_600 + minutes * 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Zone::parse("+05:30"),
+            Ok(Zone::Fixed(FixedOffset::east_opt(19_800).unwrap()))
+        );
+        assert_eq!(Zone::parse("-0800").unwrap().minutes_west(0), 480);
+        assert_eq!(Zone::parse("+9").unwrap().minutes_west(0), -540);
+        assert_eq!(Zone::parse("UTC"), Ok(Zone::Named(Tz::UTC)));
+        assert!(Zone::parse("+25:00").is_err());
+        assert!(Zone::parse("Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn named_offsets_follow_dst() {
+        let berlin = Zone::parse("Europe/Berlin").unwrap();
+        // 2021-01-15 and 2021-07-15, 12:00 UTC
+        assert_eq!(berlin.minutes_west(1_610_712_000), -60);
+        assert_eq!(berlin.minutes_west(1_626_350_400), -120);
+    }
+}